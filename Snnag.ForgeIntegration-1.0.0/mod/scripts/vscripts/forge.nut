@@ -1,4 +1,5 @@
 global function ForgeIntegration_Init
+global function ForgeIntegration_Chat
 
 void function HandleClientConnecting(entity player) {
     ForgePlugin_ClientConnecting(player.GetPlayerName(), player.GetUID())
@@ -13,6 +14,11 @@ ClServer_MessageStruct function HandleReceivedChat(ClServer_MessageStruct messag
     return message
 }
 
+// Called by the plugin
+void function ForgeIntegration_Chat(string message) {
+    Chat_ServerBroadcast(message)
+}
+
 void function ProcessLoop() {
     while (true) {
         ForgePlugin_Process()
@@ -1,9 +1,124 @@
+use forge_shared::client;
+use forge_shared::filter::CommandFilter;
 use serde::Deserialize;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub name: String,
     pub remote: SocketAddr,
+
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    /// Which commands the server can execute, checked again here in case it's compromised or misconfigured.
+    #[serde(default)]
+    pub commands: CommandFilter,
+}
+
+impl Config {
+    /// Checks the settings that would make the client misbehave rather than fail to parse.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.reconnect.validate()?;
+        self.queue.validate()?;
+        self.heartbeat.validate()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: f64,
+}
+
+impl ReconnectConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.backoff().validate()
+    }
+
+    pub fn backoff(&self) -> client::Backoff {
+        client::Backoff {
+            initial_delay: Duration::from_millis(self.initial_delay_ms),
+            max_delay: Duration::from_millis(self.max_delay_ms),
+            jitter: self.jitter,
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        let backoff = client::Backoff::default();
+        ReconnectConfig {
+            initial_delay_ms: backoff.initial_delay.as_millis() as u64,
+            max_delay_ms: backoff.max_delay.as_millis() as u64,
+            jitter: backoff.jitter,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: client::OverflowPolicy,
+}
+
+impl QueueConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.client_config().validate()
+    }
+
+    pub fn client_config(&self) -> client::QueueConfig {
+        client::QueueConfig {
+            capacity: self.capacity,
+            overflow: self.overflow,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        let queue = client::QueueConfig::default();
+        QueueConfig {
+            capacity: queue.capacity,
+            overflow: queue.overflow,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl HeartbeatConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.client_config().validate()
+    }
+
+    pub fn client_config(&self) -> client::HeartbeatConfig {
+        client::HeartbeatConfig {
+            interval: Duration::from_millis(self.interval_ms),
+            timeout: Duration::from_millis(self.timeout_ms),
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        let heartbeat = client::HeartbeatConfig::default();
+        HeartbeatConfig {
+            interval_ms: heartbeat.interval.as_millis() as u64,
+            timeout_ms: heartbeat.timeout.as_millis() as u64,
+        }
+    }
 }
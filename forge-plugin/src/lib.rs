@@ -1,24 +1,19 @@
 use crate::config::Config;
-use forge_shared::{
-    serialize, ClientEvent, ClientPacket, ReceiveBuffer, ServerEvent, ServerPacket,
-};
+use forge_shared::client::{Client, ClientConfig, ClientHandle, ReconnectPolicy};
+use forge_shared::filter::CommandFilter;
+use forge_shared::{ClientEvent, ServerEvent};
 use rrplug::bindings::squirreldatatypes::HSquirrelVM;
 use rrplug::prelude::*;
 use rrplug::wrappers::northstar::ScriptVmType;
 use rrplug::wrappers::squirrel::CSquirrelVMHandle;
 use rrplug::{call_sq_function, sq_return_null, sqfunction};
-use std::io::Write;
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::Mutex;
 
 mod config;
 
 #[derive(Debug)]
 pub struct ForgePlugin {
-    config: Option<Config>,
     sq: Mutex<PluginSqSide>,
     socket: Mutex<PluginSocketSide>,
 }
@@ -31,32 +26,31 @@ unsafe impl Send for SquirrelVMWrapper {}
 #[derive(Debug)]
 struct PluginSqSide {
     server_sqvm: Option<SquirrelVMWrapper>,
-    client_sender: Sender<ClientEvent>,
-    command_receiver: Receiver<String>,
+    client_handle: Option<ClientHandle>,
+    event_receiver: Receiver<ServerEvent>,
 }
 
 #[derive(Debug)]
 struct PluginSocketSide {
-    client_receiver: Receiver<ClientEvent>,
-    command_sender: Sender<String>,
+    client: Option<Client>,
+    commands: CommandFilter,
+    event_sender: Sender<ServerEvent>,
 }
 
 impl Plugin for ForgePlugin {
     fn new() -> Self {
-        let (client_sender, client_receiver) = channel();
-        let (command_sender, command_receiver) = channel();
+        let (event_sender, event_receiver) = channel();
 
         ForgePlugin {
-            config: None,
-
             sq: Mutex::new(PluginSqSide {
                 server_sqvm: None,
-                client_sender,
-                command_receiver,
+                client_handle: None,
+                event_receiver,
             }),
             socket: Mutex::new(PluginSocketSide {
-                client_receiver,
-                command_sender,
+                client: None,
+                commands: CommandFilter::default(),
+                event_sender,
             }),
         }
     }
@@ -69,8 +63,21 @@ impl Plugin for ForgePlugin {
 
         let config_file =
             std::fs::read_to_string("forge.toml").expect("Failed to open `forge.toml`");
-        let config = toml::from_str(&config_file).expect("Failed to parse `forge.toml`");
-        self.config = Some(config);
+        let config: Config = toml::from_str(&config_file).expect("Failed to parse `forge.toml`");
+        if let Err(err) = config.validate() {
+            panic!("Invalid `forge.toml`: {}", err);
+        }
+
+        let mut client_config = ClientConfig::new(config.name.clone(), config.remote);
+        client_config.reconnect = ReconnectPolicy::Backoff(config.reconnect.backoff());
+        client_config.queue = config.queue.client_config();
+        client_config.heartbeat = config.heartbeat.client_config();
+
+        let (client, client_handle) = Client::new(client_config);
+        self.sq.get_mut().unwrap().client_handle = Some(client_handle);
+        let socket = self.socket.get_mut().unwrap();
+        socket.client = Some(client);
+        socket.commands = config.commands;
 
         plugin_data.register_sq_functions(info_process).unwrap();
         plugin_data.register_sq_functions(info_game_start).unwrap();
@@ -84,74 +91,25 @@ impl Plugin for ForgePlugin {
     }
 
     fn main(&self) {
-        let config = self
-            .config
+        let socket = self.socket.lock().unwrap();
+        let client = socket
+            .client
             .as_ref()
             .expect("`main` was called before `initialize`");
-        let socket = self.socket.lock().unwrap();
 
-        loop {
-            log::info!("Connecting to {}", config.remote);
-            let mut stream =
-                match TcpStream::connect_timeout(&config.remote, Duration::from_secs(5)) {
-                    Ok(stream) => stream,
-                    Err(err) => {
-                        log::error!("Failed to connect: {}", err);
-                        continue;
-                    }
-                };
-
-            std::thread::scope(|s| {
-                let has_socket_closed = Arc::new(AtomicBool::new(false));
-
-                let command_sender = socket.command_sender.clone();
-                let mut recv_stream = stream.try_clone().unwrap();
-                let recv_has_socket_closed = has_socket_closed.clone();
-
-                s.spawn(move || {
-                    let mut buffer = ReceiveBuffer::new(|packet: ServerPacket| {
-                        let ignore = packet.name.map(|name| name != config.name).unwrap_or(false);
-                        if ignore {
-                            return;
-                        };
-
-                        log::info!("IN {}", packet.event);
-                        match packet.event {
-                            ServerEvent::ExecCommand { command } => {
-                                command_sender
-                                    .send(command)
-                                    .expect("Failed to send command");
-                            }
-                        }
-                    });
-
-                    while !recv_has_socket_closed.load(Ordering::Acquire) {
-                        if let Err(err) = buffer.read(&mut recv_stream) {
-                            log::error!("Read error: {}", err);
-                            recv_has_socket_closed.store(true, Ordering::Release);
-                            break;
-                        }
-                    }
-                });
-
-                // Send loop
-                while !has_socket_closed.load(Ordering::Acquire) {
-                    let Ok(event) = socket.client_receiver.recv_timeout(Duration::from_secs(5)) else { continue };
-                    log::info!("OUT {event}");
-                    let packet = ClientPacket {
-                        name: config.name.clone(),
-                        event,
-                    };
-
-                    let serialized = serialize(&packet);
-                    if let Err(err) = stream.write_all(&serialized) {
-                        log::error!("Write error: {}", err);
-                        has_socket_closed.store(true, Ordering::Release);
-                        break;
-                    }
+        let commands = socket.commands.clone();
+        let event_sender = socket.event_sender.clone();
+        client.run(move |packet| {
+            if let ServerEvent::ExecCommand { command } = &packet.event {
+                if let Err(err) = commands.check(command) {
+                    log::warn!("Not executing command from the server: {}", err);
+                    return;
                 }
-            });
-        }
+            }
+            event_sender
+                .send(packet.event)
+                .expect("Failed to send event");
+        });
     }
 
     fn on_sqvm_created(&self, sqvm_handle: &CSquirrelVMHandle) {
@@ -174,7 +132,10 @@ entry!(ForgePlugin);
 fn send_client_event(event: ClientEvent) {
     let plugin = PLUGIN.wait();
     let sq = plugin.sq.lock().unwrap();
-    sq.client_sender.send(event).expect("Failed to send event");
+    sq.client_handle
+        .as_ref()
+        .expect("Event was sent before `initialize`")
+        .send(event);
 }
 
 // Called by the ForgeIntegration mod
@@ -187,9 +148,17 @@ fn process() {
         .expect("`ForgePlugin_Process` was called while SQVM was destroyed?");
     let functions = SQFUNCTIONS.server.wait();
 
-    while let Ok(command) = sq.command_receiver.try_recv() {
-        call_sq_function!(sqvm.0, functions, "ServerCommand", command)
-            .expect("Failed to run `ServerCommand`");
+    while let Ok(event) = sq.event_receiver.try_recv() {
+        match event {
+            ServerEvent::ExecCommand { command } => {
+                call_sq_function!(sqvm.0, functions, "ServerCommand", command)
+                    .expect("Failed to run `ServerCommand`");
+            }
+            ServerEvent::Chat { message } => {
+                call_sq_function!(sqvm.0, functions, "ForgeIntegration_Chat", message)
+                    .expect("Failed to run `ForgeIntegration_Chat`");
+            }
+        }
     }
 
     sq_return_null!()
@@ -0,0 +1,319 @@
+use crate::config::{ApiConfig, LiveConfig};
+use crate::permissions::check_command;
+use crate::server::Server;
+use crate::state::{ServerInfo, States};
+use anyhow::Result;
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use forge_shared::{ClientEvent, ClientPacket, ServerEvent, ServerPacket, Target};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver};
+use tokio::sync::mpsc::UnboundedSender;
+
+#[derive(Clone)]
+struct ApiState {
+    config: &'static LiveConfig,
+    api_config: &'static ApiConfig,
+    server: &'static Server,
+    states: &'static States,
+    server_sender: UnboundedSender<ServerPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
+}
+
+#[derive(Deserialize)]
+struct ExecRequest {
+    command: String,
+}
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    message: String,
+}
+
+/// Limits the event stream to some servers or kinds of event, given as comma separated lists.
+#[derive(Deserialize)]
+struct EventFilter {
+    servers: Option<String>,
+    events: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StreamedEvent<'a> {
+    server: &'a str,
+    sequence: u64,
+    timestamp: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    event: &'a ClientEvent,
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// Serves the HTTP API until it fails.
+pub async fn serve(
+    config: &'static LiveConfig,
+    api_config: &'static ApiConfig,
+    server: &'static Server,
+    states: &'static States,
+    server_sender: UnboundedSender<ServerPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
+) -> Result<()> {
+    let state = ApiState {
+        config,
+        api_config,
+        server,
+        states,
+        server_sender,
+        packet_sender,
+    };
+
+    let app = Router::new()
+        .route("/servers", get(list_servers))
+        .route("/servers/:name", get(get_server))
+        .route("/servers/:name/exec", post(exec))
+        .route("/servers/:name/chat", post(chat))
+        .route("/exec", post(exec_all))
+        .route("/chat", post(chat_all))
+        .route("/events", get(events))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+
+    let http_server = axum::Server::try_bind(&api_config.listen)?;
+    info!("API listening on {}", api_config.listen);
+    http_server.serve(app.into_make_service()).await?;
+    Ok(())
+}
+
+async fn authorize<B>(
+    State(state): State<ApiState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    // Browsers can't set headers when opening a WebSocket, so the token can be in the query instead. Only for the
+    // WebSocket though, since query strings end up in access logs.
+    let query_token = || {
+        if request.uri().path() != "/events" {
+            return None;
+        }
+        Query::<HashMap<String, String>>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(mut query)| query.remove("token"))
+    };
+
+    match header_token.or_else(query_token) {
+        Some(token) if tokens_match(&token, &state.api_config.token) => next.run(request).await,
+        _ => ApiError(StatusCode::UNAUTHORIZED, "invalid token".to_string()).into_response(),
+    }
+}
+
+/// Compares tokens in constant time, so the correct token can't be found by timing requests.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+async fn server_info(state: &ApiState, name: &str) -> Option<ServerInfo> {
+    state
+        .states
+        .server_info(state.config.get(), state.server, name)
+        .await
+}
+
+async fn list_servers(State(state): State<ApiState>) -> Json<Vec<ServerInfo>> {
+    Json(
+        state
+            .states
+            .server_infos(state.config.get(), state.server)
+            .await,
+    )
+}
+
+async fn get_server(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<Json<ServerInfo>, ApiError> {
+    server_info(&state, &name).await.map(Json).ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            format!("unknown server \"{}\"", name),
+        )
+    })
+}
+
+async fn exec(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Json(request): Json<ExecRequest>,
+) -> Result<StatusCode, ApiError> {
+    check_command(state.config.get(), Some(&name), &request.command)
+        .map_err(|err| ApiError(StatusCode::FORBIDDEN, err.to_string()))?;
+    send_to_server(
+        &state,
+        &name,
+        ServerEvent::ExecCommand {
+            command: request.command,
+        },
+    )
+    .await
+}
+
+async fn chat(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Json(request): Json<ChatRequest>,
+) -> Result<StatusCode, ApiError> {
+    send_to_server(
+        &state,
+        &name,
+        ServerEvent::Chat {
+            message: request.message,
+        },
+    )
+    .await
+}
+
+async fn exec_all(
+    State(state): State<ApiState>,
+    Json(request): Json<ExecRequest>,
+) -> Result<StatusCode, ApiError> {
+    check_command(state.config.get(), None, &request.command)
+        .map_err(|err| ApiError(StatusCode::FORBIDDEN, err.to_string()))?;
+    Ok(send(
+        &state,
+        None,
+        ServerEvent::ExecCommand {
+            command: request.command,
+        },
+    ))
+}
+
+async fn chat_all(State(state): State<ApiState>, Json(request): Json<ChatRequest>) -> StatusCode {
+    send(
+        &state,
+        None,
+        ServerEvent::Chat {
+            message: request.message,
+        },
+    )
+}
+
+async fn events(
+    State(state): State<ApiState>,
+    Query(filter): Query<EventFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let receiver = state.packet_sender.subscribe();
+    upgrade.on_upgrade(move |socket| stream_events(socket, receiver, filter))
+}
+
+/// Sends every packet matching the filter to a WebSocket as JSON, until it's closed.
+async fn stream_events(
+    mut socket: WebSocket,
+    mut receiver: Receiver<ClientPacket>,
+    filter: EventFilter,
+) {
+    let split = |list: Option<String>| {
+        list.map(|list| {
+            list.split(',')
+                .map(|item| item.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+    };
+    let servers = split(filter.servers);
+    let events = split(filter.events);
+
+    loop {
+        let message = tokio::select! {
+            packet = receiver.recv() => match packet {
+                Ok(packet) => {
+                    if !filter_allows(&servers, &packet.name) || !filter_allows(&events, packet.event.kind()) {
+                        continue;
+                    }
+
+                    let event = StreamedEvent {
+                        server: &packet.name,
+                        sequence: packet.sequence,
+                        timestamp: packet.timestamp,
+                        kind: packet.event.kind(),
+                        event: &packet.event,
+                    };
+                    serde_json::to_string(&event).expect("Failed to serialize event")
+                }
+                // The socket can't keep up, so tell it how many packets it missed
+                Err(RecvError::Lagged(missed)) => json!({ "missed": missed }).to_string(),
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(_)) => continue,
+                _ => break,
+            },
+        };
+
+        if socket.send(ws::Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+
+    debug!("Event stream closed");
+}
+
+fn filter_allows(filter: &Option<Vec<String>>, item: &str) -> bool {
+    match filter {
+        Some(allowed) => allowed.iter().any(|allowed| allowed == item),
+        None => true,
+    }
+}
+
+async fn send_to_server(
+    state: &ApiState,
+    name: &str,
+    event: ServerEvent,
+) -> Result<StatusCode, ApiError> {
+    let Some(info) = server_info(state, name).await else {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("unknown server \"{}\"", name),
+        ));
+    };
+    if !info.connected {
+        return Err(ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("server \"{}\" isn't connected", name),
+        ));
+    }
+
+    Ok(send(state, Some(name.to_string()), event))
+}
+
+fn send(state: &ApiState, name: Option<String>, event: ServerEvent) -> StatusCode {
+    let target = Target::from_name(name);
+    state
+        .server_sender
+        .send(ServerPacket { target, event })
+        .expect("Failed to send server packet");
+    StatusCode::ACCEPTED
+}
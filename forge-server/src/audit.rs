@@ -0,0 +1,166 @@
+use crate::config::ScheduleConfig;
+use crate::schedule;
+use anyhow::Result;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An administrative action taken from Discord, as one line of JSON. `time` is in milliseconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditEntry {
+    pub time: u64,
+    pub user: u64,
+    pub user_name: String,
+    pub guild: Option<u64>,
+    pub channel: u64,
+    /// The server the action was for, or `None` for every server or a group.
+    pub server: Option<String>,
+    /// The group of servers the action was for, if it was for one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub action: AuditAction,
+    #[serde(flatten)]
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AuditAction {
+    Exec { command: String },
+    Announce { message: String },
+    AddSchedule { schedule: ScheduleConfig },
+    RemoveSchedule { id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed { reason: String },
+}
+
+impl AuditEntry {
+    pub fn new(
+        user: u64,
+        user_name: String,
+        guild: Option<u64>,
+        channel: u64,
+        server: Option<String>,
+        action: AuditAction,
+        outcome: AuditOutcome,
+    ) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        AuditEntry {
+            time,
+            user,
+            user_name,
+            guild,
+            channel,
+            server,
+            group: None,
+            action,
+            outcome,
+        }
+    }
+
+    /// Describes the entry with Discord's Markdown, on one line.
+    pub fn describe(&self) -> String {
+        let on = match (&self.server, &self.group) {
+            (Some(server), _) => format!("on **{}**", server),
+            (None, Some(group)) => format!("on group **{}**", group),
+            (None, None) => "on every server".to_string(),
+        };
+        let action = match &self.action {
+            AuditAction::Exec { command } => format!("executed `{}` {}", command, on),
+            AuditAction::Announce { message } => format!("announced \"{}\" {}", message, on),
+            AuditAction::AddSchedule { schedule } => format!(
+                "added a schedule {}",
+                schedule::describe(self.server.as_deref(), schedule)
+            ),
+            AuditAction::RemoveSchedule { id } => format!("removed schedule **#{}**", id),
+        };
+        let outcome = match &self.outcome {
+            AuditOutcome::Succeeded => String::new(),
+            AuditOutcome::Failed { reason } => format!(" (failed: {})", reason),
+        };
+        format!(
+            "<t:{}:f> <@{}> {}{}",
+            self.time / 1000,
+            self.user,
+            action,
+            outcome
+        )
+    }
+}
+
+/// An append-only file of every administrative action taken from Discord.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens an audit log to add entries to, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, entry: &AuditEntry) {
+        let mut line = serde_json::to_string(entry).expect("Failed to serialize audit entry");
+        line.push('\n');
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write to audit log: {}", err);
+        }
+    }
+
+    /// Finds the last `count` entries by a user and for a server that `visible` allows, oldest first. Actions for every
+    /// server count as being for each server.
+    pub fn recent(
+        &self,
+        user: Option<u64>,
+        server: Option<&str>,
+        visible: impl Fn(&AuditEntry) -> bool,
+        count: usize,
+    ) -> Result<Vec<AuditEntry>> {
+        let log = std::fs::read_to_string(&self.path)?;
+
+        let mut entries = Vec::new();
+        for (index, line) in log.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Invalid audit entry on line {}: {}", index + 1, err);
+                    continue;
+                }
+            };
+
+            let user_matches = user.is_none() || user == Some(entry.user);
+            let server_matches = match (server, &entry.server) {
+                (Some(server), Some(entry_server)) => server == entry_server,
+                _ => true,
+            };
+            if user_matches && server_matches && visible(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        let skip = entries.len().saturating_sub(count);
+        Ok(entries.split_off(skip))
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(unix)]
+use forge_server::control::{Request, Response};
+#[cfg(unix)]
+use forge_server::sink::format_time;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "forge.sock";
+
+#[cfg(unix)]
+struct Args {
+    socket: PathBuf,
+    json: bool,
+    request: Request,
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The control socket is only supported on Unix");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+fn main() {
+    let mut args = std::env::args();
+    let exe_name = args.next().unwrap();
+
+    let Some(args) = parse_args(args) else {
+        eprintln!("Usage: {} [--socket <path>] [--json] <command>", exe_name);
+        eprintln!();
+        eprintln!("Commands:");
+        eprintln!("  servers                      List configured and connected servers");
+        eprintln!("  roster <server>              Show the map, mode and players of a server");
+        eprintln!("  exec <server> <command>...   Execute a command on a server");
+        eprintln!("  execall <command>...         Execute a command on every server");
+        eprintln!("  reload                       Reload the config file");
+        eprintln!("  tail [server]...             Show events as they happen");
+        eprintln!();
+        std::process::exit(1);
+    };
+
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut socket = PathBuf::from(DEFAULT_SOCKET);
+    let mut json = false;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => socket = args.next()?.into(),
+            "--json" => json = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let (command, rest) = positional.split_first()?;
+    let request = match (command.as_str(), rest) {
+        ("servers", []) => Request::Servers,
+        ("roster", [server]) => Request::Roster {
+            server: server.clone(),
+        },
+        ("exec", [server, command @ ..]) if !command.is_empty() => Request::Exec {
+            server: Some(server.clone()),
+            command: command.join(" "),
+        },
+        ("execall", command) if !command.is_empty() => Request::Exec {
+            server: None,
+            command: command.join(" "),
+        },
+        ("reload", []) => Request::Reload,
+        ("tail", servers) => Request::Tail {
+            servers: (!servers.is_empty()).then(|| servers.to_vec()),
+        },
+        _ => return None,
+    };
+
+    Some(Args {
+        socket,
+        json,
+        request,
+    })
+}
+
+#[cfg(unix)]
+fn run(args: Args) -> Result<(), String> {
+    let stream = UnixStream::connect(&args.socket)
+        .map_err(|err| format!("failed to connect to {}: {}", args.socket.display(), err))?;
+
+    let mut request = serde_json::to_string(&args.request).expect("Failed to serialize request");
+    request.push('\n');
+    (&stream)
+        .write_all(request.as_bytes())
+        .map_err(|err| format!("failed to send request: {}", err))?;
+
+    // Only a tail gets more than one response
+    let is_tail = matches!(args.request, Request::Tail { .. });
+    for line in BufReader::new(&stream).lines() {
+        let line = line.map_err(|err| format!("failed to read response: {}", err))?;
+        let response = serde_json::from_str::<Response>(&line)
+            .map_err(|err| format!("invalid response: {}", err))?;
+
+        if let Response::Error { message } = response {
+            return Err(message);
+        }
+        if args.json {
+            println!("{}", line);
+        } else {
+            print_response(&response);
+        }
+        if !is_tail {
+            return Ok(());
+        }
+    }
+
+    Err("the server closed the connection".to_string())
+}
+
+#[cfg(unix)]
+fn print_response(response: &Response) {
+    match response {
+        Response::Servers { servers } => {
+            if servers.is_empty() {
+                println!("No servers are configured.");
+            }
+            for server in servers {
+                let status = match (server.connected, server.latency_ms) {
+                    (true, Some(latency)) => format!("connected ({} ms)", latency),
+                    (true, None) => "connected".to_string(),
+                    (false, _) => "disconnected".to_string(),
+                };
+                println!(
+                    "{}: {}, {} player(s)",
+                    server.name,
+                    status,
+                    server.state.players.len()
+                );
+            }
+        }
+        Response::Roster { server } => {
+            let map = server.state.map.as_deref().unwrap_or("unknown");
+            let mode = server.state.mode.as_deref().unwrap_or("unknown");
+            println!("{} on {}", mode, map);
+            if server.state.players.is_empty() {
+                println!("No players.");
+            }
+            for player in &server.state.players {
+                println!("{} ({})", player.name, player.uid);
+            }
+        }
+        Response::Event { packet } => {
+            println!(
+                "[{}] {}: {}",
+                format_time(packet.timestamp),
+                packet.name,
+                packet.event
+            );
+        }
+        Response::Missed { count } => println!("{} event(s) were missed", count),
+        Response::Ok => println!("Done."),
+        Response::Error { message } => println!("Error: {}", message),
+    }
+}
@@ -1,22 +1,532 @@
-use serde::Deserialize;
+use crate::permissions::wildcard_matches;
+use crate::sink::Category;
+use anyhow::{anyhow, bail, Result};
+use forge_shared::filter::CommandFilter;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// Discord only allows a response to be edited for 15 minutes, so confirmations have to time out before then.
+const MAX_CONFIRM_TIMEOUT_SECS: u64 = 14 * 60;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub listen: SocketAddr,
+    /// The main Discord bot, which displays and takes commands for every server that no bot in `bots` owns.
     pub discord_token: String,
     pub discord_application: u64,
+    /// More Discord bots, each with its own servers.
+    #[serde(default)]
+    pub bots: Vec<BotConfig>,
+
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+
+    pub matrix: Option<MatrixConfig>,
+    pub irc: Option<IrcConfig>,
+    pub api: Option<ApiConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub audit: Option<AuditConfig>,
+    /// Path of the Unix socket `forgectl` connects to.
+    pub control_socket: Option<PathBuf>,
+    /// Path of a JSONL file to record every packet to, which can be replayed with `--replay`.
+    pub record: Option<PathBuf>,
+    /// Path of a JSON file to keep schedules added with `/schedule` in.
+    pub schedule_file: Option<PathBuf>,
+    /// Schedules run on every server.
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    /// Which commands can be executed on every server.
+    #[serde(default)]
+    pub commands: CommandFilter,
+    /// Who can execute which commands from Discord. Anyone who can use the commands can execute anything if this is
+    /// empty.
+    #[serde(default)]
+    pub permissions: Vec<PermissionConfig>,
+    /// Commands that have to be confirmed before they're executed from Discord.
+    #[serde(default)]
+    pub confirm: ConfirmConfig,
+    /// Discord guilds to register the commands in, instead of registering them globally. When there are any, commands
+    /// only work in these guilds and can only control the servers each one lists.
+    #[serde(default)]
+    pub guilds: Vec<GuildConfig>,
+
+    /// Where each category of messages from every server is displayed, unless the server says otherwise.
+    #[serde(default)]
+    pub channels: ChannelsConfig,
 
     pub servers: HashMap<String, ServerConfig>,
+    /// Named sets of servers that commands can be sent to together, like `eu = ["eu-1", "eu-2"]`. Servers are also
+    /// in a group for each of their tags.
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
 
     pub maps: HashMap<String, String>,
     pub modes: HashMap<String, String>,
 }
 
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let config: Config = toml::from_str(&std::fs::read_to_string(path)?)?;
+
+        let server_schedules = config.servers.values().flat_map(|server| &server.schedules);
+        for schedule in config.schedules.iter().chain(server_schedules) {
+            schedule.validate()?;
+        }
+        for (index, bot) in config.bots.iter().enumerate() {
+            if config.bots[..index]
+                .iter()
+                .any(|other| other.name == bot.name)
+            {
+                bail!("there's more than one bot named \"{}\"", bot.name);
+            }
+        }
+        config.heartbeat.validate()?;
+        if let Some(irc) = &config.irc {
+            irc.validate()?;
+        }
+        if let Some(api) = &config.api {
+            if api.token.trim().is_empty() {
+                bail!("the API token can't be empty");
+            }
+        }
+        if config.confirm.timeout_secs > MAX_CONFIRM_TIMEOUT_SECS {
+            bail!(
+                "confirmations can't time out after more than {} seconds",
+                MAX_CONFIRM_TIMEOUT_SECS
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// The servers in a group, sorted, or `None` if there's no such group.
+    pub fn group(&self, name: &str) -> Option<Vec<String>> {
+        let mut servers = self.groups.get(name).cloned();
+        for (server, server_config) in &self.servers {
+            if server_config.tags.iter().any(|tag| tag == name) {
+                servers.get_or_insert_with(Vec::new).push(server.clone());
+            }
+        }
+
+        let mut servers = servers?;
+        servers.sort_unstable();
+        servers.dedup();
+        Some(servers)
+    }
+
+    /// The name of the bot in `bots` that owns a server, or `None` if the main bot does.
+    pub fn bot(&self, server: &str) -> Option<&str> {
+        self.bots
+            .iter()
+            .find(|bot| {
+                bot.servers
+                    .iter()
+                    .any(|pattern| wildcard_matches(pattern, server))
+            })
+            .map(|bot| bot.name.as_str())
+    }
+
+    /// The Discord channel a category of messages from a server is displayed in, if any. The server's `channels` come
+    /// first, then the global ones, then the server's `channel`. Moderation goes to the audit channel and command output
+    /// isn't displayed unless they're set.
+    pub fn channel(&self, server: &str, category: Category) -> Option<u64> {
+        let server_config = self.servers.get(server)?;
+        let route = server_config
+            .channels
+            .route(category)
+            .or_else(|| self.channels.route(category));
+        match route {
+            Some(ChannelRoute::Channel(channel)) => Some(channel),
+            Some(ChannelRoute::Enabled(false)) => None,
+            Some(ChannelRoute::Enabled(true)) => server_config.channel,
+            None => match category {
+                Category::Moderation => self.audit.as_ref().and_then(|audit| audit.channel),
+                Category::CommandOutput => None,
+                _ => server_config.channel,
+            },
+        }
+    }
+
+    /// The names of every group and tag.
+    pub fn group_names(&self) -> Vec<String> {
+        let tags = self.servers.values().flat_map(|server| &server.tags);
+        let mut names: Vec<String> = self.groups.keys().chain(tags).cloned().collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+}
+
+/// The current config, which can be reloaded from its file while running.
+///
+/// Settings only used at startup, like listen addresses and tokens, keep their original values until
+/// a restart.
+pub struct LiveConfig {
+    path: PathBuf,
+    // Replaced configs are leaked, since anything could still be using them
+    current: RwLock<&'static Config>,
+}
+
+impl LiveConfig {
+    pub fn new(path: PathBuf, config: Config) -> Self {
+        LiveConfig {
+            path,
+            current: RwLock::new(Box::leak(Box::new(config))),
+        }
+    }
+
+    pub fn get(&self) -> &'static Config {
+        *self.current.read().unwrap()
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let config = Config::load(&self.path)?;
+        *self.current.write().unwrap() = Box::leak(Box::new(config));
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct ServerConfig {
-    pub channel: u64,
+    pub channel: Option<u64>,
+    /// Where each category of messages is displayed, instead of `channel`.
+    #[serde(default)]
+    pub channels: ChannelsConfig,
+    pub matrix_room: Option<String>,
+    pub irc_channel: Option<String>,
+    /// Groups this server is in, like `eu` or `fd`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    /// Which commands can be executed on this server, as well as the global filter.
+    #[serde(default)]
+    pub commands: CommandFilter,
+}
+
+/// Discord channels for each category of messages. Any that aren't set fall back to the defaults.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ChannelsConfig {
+    pub chat: Option<ChannelRoute>,
+    pub joins: Option<ChannelRoute>,
+    pub matches: Option<ChannelRoute>,
+    pub warnings: Option<ChannelRoute>,
+    pub moderation: Option<ChannelRoute>,
+    pub command_output: Option<ChannelRoute>,
+}
+
+impl ChannelsConfig {
+    fn route(&self, category: Category) -> Option<ChannelRoute> {
+        match category {
+            Category::Chat => self.chat,
+            Category::Joins => self.joins,
+            Category::Matches => self.matches,
+            Category::Warnings => self.warnings,
+            Category::Moderation => self.moderation,
+            Category::CommandOutput => self.command_output,
+        }
+    }
+}
+
+/// Where a category of messages goes: a channel ID, `false` to not display them, or `true` for the server's `channel`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum ChannelRoute {
+    Channel(u64),
+    Enabled(bool),
+}
+
+/// Something done at certain times, like `cron = "0 0 4 * * *"` and `exec = "map mp_glitch"`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    #[serde(flatten)]
+    pub when: ScheduleTime,
+    #[serde(flatten)]
+    pub action: ScheduleAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScheduleTime {
+    /// A cron expression in UTC, starting with the seconds, like `0 30 * * * *` for half past every hour.
+    Cron(String),
+    /// Every so many minutes, counted from the Unix epoch.
+    IntervalMinutes(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScheduleAction {
+    /// Executes a command.
+    Exec(String),
+    /// Shows a message in the game's chat.
+    Announce(String),
+}
+
+impl ScheduleConfig {
+    pub fn validate(&self) -> Result<()> {
+        match &self.when {
+            ScheduleTime::Cron(expression) => {
+                cron::Schedule::from_str(expression).map_err(|err| {
+                    anyhow!("invalid cron expression \"{}\": {}", expression, err)
+                })?;
+            }
+            ScheduleTime::IntervalMinutes(0) => bail!("schedule intervals can't be zero"),
+            ScheduleTime::IntervalMinutes(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Lets some Discord users and roles execute commands matching `commands` on servers matching `servers`. Both can
+/// use `*` and `?` wildcards, like `kick *`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PermissionConfig {
+    #[serde(default)]
+    pub users: Vec<u64>,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    #[serde(default = "PermissionConfig::all")]
+    pub servers: Vec<String>,
+    pub commands: Vec<String>,
+}
+
+impl PermissionConfig {
+    fn all() -> Vec<String> {
+        vec!["*".to_string()]
+    }
+}
+
+/// A Discord bot besides the main one, which owns the servers matching `servers`. They can use `*` and `?` wildcards,
+/// and a server matching more than one bot belongs to the first.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct BotConfig {
+    /// A name for the bot in logs and metrics.
+    pub name: String,
+    pub token: String,
+    pub application: u64,
+    pub servers: Vec<String>,
+}
+
+/// A Discord guild to register the commands in, which can control servers matching `servers`. They can use `*` and `?`
+/// wildcards, like `eu-*`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct GuildConfig {
+    pub id: u64,
+    #[serde(default = "PermissionConfig::all")]
+    pub servers: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ConfirmConfig {
+    /// Patterns like `quit*`, which can use `*` and `?` wildcards.
+    pub commands: Vec<String>,
+    pub timeout_secs: u64,
+}
+
+impl ConfirmConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for ConfirmConfig {
+    fn default() -> Self {
+        ConfirmConfig {
+            commands: Vec::new(),
+            timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatrixConfig {
+    /// Base URL of the homeserver, like `https://matrix.example.org`.
+    pub homeserver: String,
+    pub access_token: String,
+    /// Users allowed to execute commands, like `@admin:example.org`.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct IrcConfig {
+    /// Address of the IRC server, like `irc.example.org:6667`.
+    pub server: String,
+    pub nickname: String,
+    pub password: Option<String>,
+    /// Users allowed to execute commands, as masks like `*!*@admin.example.org`.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+impl IrcConfig {
+    /// Checks that every admin mask includes a user and host, since anyone can use any free nickname.
+    pub fn validate(&self) -> Result<()> {
+        for mask in &self.admins {
+            let has_host = mask
+                .split_once('!')
+                .map(|(_, user_host)| user_host.contains('@'))
+                .unwrap_or(false);
+            if !has_host {
+                bail!(
+                    "IRC admin \"{}\" has to be a mask like nick!user@host, since anyone can take a nickname",
+                    mask
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ApiConfig {
+    pub listen: SocketAddr,
+    /// Sent by clients in an `Authorization: Bearer <token>` header.
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditConfig {
+    /// Path of a JSONL file to add every administrative action to, which `/audit` searches.
+    pub file: Option<PathBuf>,
+    /// Discord channel each administrative action is also posted in.
+    pub channel: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl HeartbeatConfig {
+    /// Checks that pings are sent at all, and that clients aren't disconnected before they can reply to one.
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_ms == 0 {
+            bail!("the heartbeat interval can't be zero");
+        }
+        if self.timeout_ms <= self.interval_ms {
+            bail!("the heartbeat timeout has to be longer than the interval");
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval_ms: 10000,
+            timeout_ms: 30000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            listen = "127.0.0.1:0"
+            discord-token = ""
+            discord-application = 0
+
+            [channels]
+            matches = 20
+            warnings = false
+
+            [servers.linked]
+            channel = 10
+
+            [servers.linked.channels]
+            joins = 11
+            matches = true
+
+            [servers.other]
+            channel = 40
+
+            [servers.unlinked]
+
+            [maps]
+            [modes]
+
+            {}
+            "#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn server_channels_come_first() {
+        let config = config("");
+        assert_eq!(config.channel("linked", Category::Joins), Some(11));
+        // `true` means the server's channel, even though the global one is set
+        assert_eq!(config.channel("linked", Category::Matches), Some(10));
+        assert_eq!(config.channel("other", Category::Matches), Some(20));
+        assert_eq!(config.channel("linked", Category::Warnings), None);
+    }
+
+    #[test]
+    fn unset_categories_use_the_server_channel() {
+        let config = config("");
+        assert_eq!(config.channel("linked", Category::Chat), Some(10));
+        assert_eq!(config.channel("other", Category::Joins), Some(40));
+        assert_eq!(config.channel("unlinked", Category::Chat), None);
+        assert_eq!(config.channel("unlinked", Category::Matches), Some(20));
+        assert_eq!(config.channel("missing", Category::Chat), None);
+    }
+
+    #[test]
+    fn moderation_uses_the_audit_channel() {
+        assert_eq!(config("").channel("linked", Category::Moderation), None);
+
+        let config = config("[audit]\nchannel = 30");
+        assert_eq!(config.channel("linked", Category::Moderation), Some(30));
+        assert_eq!(config.channel("unlinked", Category::Moderation), Some(30));
+    }
+
+    #[test]
+    fn command_output_is_only_displayed_when_set() {
+        let mut config = config("");
+        assert_eq!(config.channel("linked", Category::CommandOutput), None);
+
+        config.channels.command_output = Some(ChannelRoute::Enabled(true));
+        config.channels.moderation = Some(ChannelRoute::Channel(50));
+        assert_eq!(config.channel("linked", Category::CommandOutput), Some(10));
+        assert_eq!(config.channel("linked", Category::Moderation), Some(50));
+    }
 }
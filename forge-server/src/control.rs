@@ -0,0 +1,259 @@
+use crate::config::LiveConfig;
+use crate::permissions::check_command;
+use crate::server::Server;
+use crate::state::{ServerInfo, States};
+use anyhow::{anyhow, Result};
+use forge_shared::{ClientPacket, ServerEvent, ServerPacket, Target};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::DirBuilder;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A request sent to the control socket, as one line of JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+    Servers,
+    Roster {
+        server: String,
+    },
+    /// Executes a command on one server, or every server if none is given.
+    Exec {
+        server: Option<String>,
+        command: String,
+    },
+    Reload,
+    /// Streams events from some servers, or every server if none are given, until the connection is closed.
+    Tail {
+        servers: Option<Vec<String>>,
+    },
+}
+
+/// A response from the control socket, as one line of JSON. A tail gets a response for each event.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Servers {
+        servers: Vec<ServerInfo>,
+    },
+    Roster {
+        server: ServerInfo,
+    },
+    Event {
+        packet: ClientPacket,
+    },
+    /// The tail couldn't keep up, and this many events were skipped.
+    Missed {
+        count: u64,
+    },
+    Ok,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Clone)]
+struct ControlState {
+    config: &'static LiveConfig,
+    server: &'static Server,
+    states: &'static States,
+    server_sender: UnboundedSender<ServerPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
+}
+
+/// Accepts connections to the control socket until it fails.
+pub async fn serve(
+    path: &Path,
+    config: &'static LiveConfig,
+    server: &'static Server,
+    states: &'static States,
+    server_sender: UnboundedSender<ServerPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
+) -> Result<()> {
+    // The socket file is left behind when the server stops, and binding fails if it exists
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    info!("Control socket listening on {}", path.display());
+
+    let state = ControlState {
+        config,
+        server,
+        states,
+        server_sender,
+        packet_sender,
+    };
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, state).await {
+                error!("Control connection failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Binds a socket at `path` that only the user running the server can connect to.
+///
+/// Anyone who can connect can execute commands, so the socket is bound in a directory only this user can enter and
+/// given its permissions there, before it's moved into place. Otherwise it could be connected to between being bound
+/// and having its permissions changed.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} isn't a file path", path.display()))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private_path = dir.join(file_name);
+    let result = UnixListener::bind(&private_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&private_path, path)?;
+            Ok(listener)
+        });
+
+    if let Err(err) = std::fs::remove_dir_all(&dir) {
+        error!("Failed to remove {}: {}", dir.display(), err);
+    }
+    result
+}
+
+async fn handle_connection(stream: UnixStream, state: ControlState) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let message = format!("invalid request: {}", err);
+                send_response(&mut write, &Response::Error { message }).await?;
+                continue;
+            }
+        };
+        debug!("Control request: {:?}", request);
+
+        let response = match request {
+            Request::Servers => Response::Servers {
+                servers: state
+                    .states
+                    .server_infos(state.config.get(), state.server)
+                    .await,
+            },
+            Request::Roster { server } => {
+                match state
+                    .states
+                    .server_info(state.config.get(), state.server, &server)
+                    .await
+                {
+                    Some(server) => Response::Roster { server },
+                    None => error_response(format!("unknown server \"{}\"", server)),
+                }
+            }
+            Request::Exec { server, command } => exec(&state, server, command).await,
+            Request::Reload => match state.config.reload() {
+                Ok(()) => {
+                    info!("Reloaded config");
+                    Response::Ok
+                }
+                Err(err) => error_response(format!("failed to reload config: {}", err)),
+            },
+            Request::Tail { servers } => {
+                let receiver = state.packet_sender.subscribe();
+                return tail(lines, write, receiver, servers).await;
+            }
+        };
+
+        send_response(&mut write, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn exec(state: &ControlState, server: Option<String>, command: String) -> Response {
+    if let Err(err) = check_command(state.config.get(), server.as_deref(), &command) {
+        return error_response(err.to_string());
+    }
+    if let Some(name) = &server {
+        let info = state
+            .states
+            .server_info(state.config.get(), state.server, name)
+            .await;
+        match info {
+            None => return error_response(format!("unknown server \"{}\"", name)),
+            Some(info) if !info.connected => {
+                return error_response(format!("server \"{}\" isn't connected", name))
+            }
+            Some(_) => {}
+        }
+    }
+
+    info!("Executing `{}` from the control socket", command);
+    state
+        .server_sender
+        .send(ServerPacket {
+            target: Target::from_name(server),
+            event: ServerEvent::ExecCommand { command },
+        })
+        .expect("Failed to send server packet");
+    Response::Ok
+}
+
+/// Sends every packet from the listed servers until the connection is closed.
+async fn tail(
+    mut lines: Lines<BufReader<OwnedReadHalf>>,
+    mut write: OwnedWriteHalf,
+    mut receiver: Receiver<ClientPacket>,
+    servers: Option<Vec<String>>,
+) -> Result<()> {
+    loop {
+        let response = tokio::select! {
+            packet = receiver.recv() => match packet {
+                Ok(packet) => {
+                    if let Some(servers) = &servers {
+                        if !servers.contains(&packet.name) {
+                            continue;
+                        }
+                    }
+                    Response::Event { packet }
+                }
+                Err(RecvError::Lagged(count)) => Response::Missed { count },
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // Anything else sent during a tail is ignored
+            line = lines.next_line() => match line? {
+                Some(_) => continue,
+                None => return Ok(()),
+            },
+        };
+
+        send_response(&mut write, &response).await?;
+    }
+}
+
+fn error_response(message: String) -> Response {
+    Response::Error { message }
+}
+
+async fn send_response(write: &mut OwnedWriteHalf, response: &Response) -> Result<()> {
+    let mut line = serde_json::to_string(response).expect("Failed to serialize response");
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    Ok(())
+}
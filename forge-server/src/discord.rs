@@ -0,0 +1,1728 @@
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditOutcome};
+use crate::config::{LiveConfig, ScheduleAction, ScheduleConfig, ScheduleTime};
+use crate::metrics::Metrics;
+use crate::permissions::{
+    check_announce, check_audit, check_execute, guild_controls, needs_confirmation, DiscordUser,
+};
+use crate::schedule::Scheduler;
+use crate::server::Server;
+use crate::sink::{Category, Message, Sink};
+use anyhow::{anyhow, bail, Result};
+use forge_shared::{ServerEvent, ServerPacket};
+use log::{debug, error, info};
+use serenity::async_trait;
+use serenity::http::Http;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use serenity::utils::Color;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+
+// How many entries `/audit` shows if it isn't given a count, and the most it can show.
+const DEFAULT_AUDIT_COUNT: i64 = 10;
+const MAX_AUDIT_COUNT: i64 = 50;
+// Discord's limits on the length of a message's content and an embed's description.
+const MESSAGE_CONTENT_LIMIT: usize = 2000;
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+// Room kept at the end of a description that doesn't fit, to say how many lines were left out.
+const OMITTED_LINES_LENGTH: usize = 32;
+// How long to wait for more messages after the first of a burst, so they can be posted together.
+const BATCH_WINDOW: Duration = Duration::from_secs(1);
+// How many messages can be waiting to be posted in one channel before the oldest are dropped.
+const MAX_QUEUED_MESSAGES: usize = 100;
+// Discord's limit on the number of choices an autocomplete response can have.
+const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
+
+/// Displays messages in the Discord channel linked to each server owned by a bot, which is the main bot if `bot` is
+/// `None`.
+///
+/// Each channel has its own queue of messages, so one that's being rate limited doesn't hold up the others. Since
+/// messages are posted after [`Sink::send`] returns, each post is recorded in `metrics` under `name` by the queue.
+pub struct DiscordSink {
+    config: &'static LiveConfig,
+    bot: Option<&'static str>,
+    name: &'static str,
+    metrics: &'static Metrics,
+    http: Arc<Http>,
+    queues: Mutex<HashMap<u64, Arc<ChannelQueue>>>,
+}
+
+impl DiscordSink {
+    pub fn new(
+        config: &'static LiveConfig,
+        bot: Option<&'static str>,
+        name: &'static str,
+        metrics: &'static Metrics,
+        http: Arc<Http>,
+    ) -> Self {
+        DiscordSink {
+            config,
+            bot,
+            name,
+            metrics,
+            http,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for DiscordSink {
+    /// Queues a message to be posted. Errors posting it are only logged.
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        let config = self.config.get();
+        // Every bot's sink gets every message, but only the bot that owns the server displays it
+        if config.bot(server) != self.bot {
+            return Ok(());
+        }
+        let server_config = config
+            .servers
+            .get(server)
+            .ok_or_else(|| anyhow!("unknown server \"{}\"", server))?;
+        let Some(channel) = config.channel(server, message.category()) else {
+            return Ok(());
+        };
+        // Other channels can be shared by several servers, so they show which one each message is from
+        let message = if server_config.channel == Some(channel) {
+            message.clone()
+        } else {
+            with_server(server, message)
+        };
+
+        let queue = self
+            .queues
+            .lock()
+            .unwrap()
+            .entry(channel)
+            .or_insert_with(|| {
+                let queue = Arc::new(ChannelQueue::default());
+                let poster = Poster {
+                    http: self.http.clone(),
+                    name: self.name,
+                    metrics: self.metrics,
+                };
+                tokio::spawn(run_channel_queue(poster, ChannelId(channel), queue.clone()));
+                queue
+            })
+            .clone();
+        queue.push(message);
+        Ok(())
+    }
+}
+
+/// Puts the name of the server a message is from in front of it.
+fn with_server(server: &str, message: &Message) -> Message {
+    let mut message = message.clone();
+    match &mut message {
+        Message::Chat { text, .. } => *text = format!("[{}] {}", server, text),
+        Message::Event { description, .. } | Message::Warning { description } => {
+            *description = format!("[{}] {}", server, description)
+        }
+    }
+    message
+}
+
+/// Messages waiting to be posted in one channel.
+#[derive(Default)]
+struct ChannelQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Message>,
+    // How many messages have been dropped since the last batch was taken
+    dropped: usize,
+}
+
+impl ChannelQueue {
+    fn push(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() >= MAX_QUEUED_MESSAGES {
+            state.messages.pop_front();
+            state.dropped += 1;
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Takes the next messages to post as one: a run of messages of the same kind and category, joined into one that
+    /// fits in a Discord message. A message that's too long on its own is cut short. Chat times are put in the text,
+    /// so the batch's `sent_at` is always `None`. If messages were dropped, a warning saying so comes first.
+    fn take_batch(&self) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        if state.dropped > 0 {
+            let dropped = std::mem::take(&mut state.dropped);
+            return Some(Message::Warning {
+                description: format!(
+                    "{dropped} message(s) were dropped because Discord couldn't keep up."
+                ),
+            });
+        }
+
+        let mut last = state.messages.pop_front()?;
+        let limit = match last {
+            Message::Chat { .. } => MESSAGE_CONTENT_LIMIT,
+            _ => EMBED_DESCRIPTION_LIMIT,
+        };
+        let mut text = truncate(batch_line(&last), limit);
+        while let Some(next) = state.messages.front() {
+            let line = batch_line(next);
+            if next.category() != last.category()
+                || text.chars().count() + 1 + line.chars().count() > limit
+            {
+                break;
+            }
+            text.push('\n');
+            text.push_str(&line);
+            last = state.messages.pop_front().unwrap();
+        }
+
+        Some(match last {
+            Message::Chat { .. } => Message::Chat {
+                text,
+                sent_at: None,
+            },
+            Message::Event {
+                category,
+                timestamp,
+                ..
+            } => Message::Event {
+                category,
+                description: text,
+                timestamp,
+            },
+            Message::Warning { .. } => Message::Warning { description: text },
+        })
+    }
+}
+
+/// A message's line in a batch.
+fn batch_line(message: &Message) -> String {
+    match message {
+        Message::Chat { text, sent_at } => match sent_at {
+            Some(sent_at) => format!("<t:{}:T> {text}", sent_at / 1000),
+            None => text.clone(),
+        },
+        Message::Event { description, .. } => description.clone(),
+        Message::Warning { description } => description.clone(),
+    }
+}
+
+/// Cuts text longer than `limit` characters short, ending it with an ellipsis.
+fn truncate(text: String, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text;
+    }
+    let mut text: String = text.chars().take(limit - 1).collect();
+    text.push('…');
+    text
+}
+
+/// Keeps as many lines from the start of some text as fit in an embed's description, saying how many were left out.
+fn fit_description(text: &str) -> String {
+    if text.chars().count() <= EMBED_DESCRIPTION_LIMIT {
+        return text.to_string();
+    }
+
+    let lines: Vec<&str> = text.lines().collect();
+    let mut description = String::new();
+    let mut length = 0;
+    let mut kept = 0;
+    for line in &lines {
+        length += line.chars().count() + 1;
+        if length > EMBED_DESCRIPTION_LIMIT - OMITTED_LINES_LENGTH {
+            break;
+        }
+        description.push_str(line);
+        description.push('\n');
+        kept += 1;
+    }
+    description.push_str(&format!("…and {} more.", lines.len() - kept));
+    description
+}
+
+/// What a channel's queue needs to post messages and record how it went.
+struct Poster {
+    http: Arc<Http>,
+    name: &'static str,
+    metrics: &'static Metrics,
+}
+
+/// Posts the messages queued for a channel as they come, in batches.
+async fn run_channel_queue(poster: Poster, channel: ChannelId, queue: Arc<ChannelQueue>) {
+    loop {
+        queue.notify.notified().await;
+        tokio::time::sleep(BATCH_WINDOW).await;
+
+        // Serenity waits out rate limits before posting, so more messages can queue up in the meantime
+        while let Some(message) = queue.take_batch() {
+            let start = Instant::now();
+            let res = post(&poster.http, channel, &message).await;
+            poster
+                .metrics
+                .observe_send(poster.name, start.elapsed(), res.is_err());
+            if let Err(err) = res {
+                error!("Failed to post in channel {}: {}", channel, err);
+            }
+        }
+    }
+}
+
+/// Posts a message from [`ChannelQueue::take_batch`].
+async fn post(http: &Http, channel: ChannelId, message: &Message) -> Result<()> {
+    channel
+        .send_message(http, |m| match message {
+            Message::Chat { text, .. } => m.content(text),
+            Message::Event {
+                description,
+                timestamp,
+                ..
+            } => m.embed(|embed| {
+                let timestamp = Timestamp::from_unix_timestamp((timestamp / 1000) as i64)
+                    .unwrap_or_else(|_| Timestamp::now());
+                embed.description(description).timestamp(timestamp)
+            }),
+            Message::Warning { description } => {
+                m.embed(|embed| embed.color(Color::new(0xFFA500)).description(description))
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// Handles the commands of one bot, which is the main bot if `bot` is `None`, for the servers it owns.
+pub struct Handler {
+    config: &'static LiveConfig,
+    bot: Option<&'static str>,
+    server: &'static Server,
+    scheduler: &'static Scheduler,
+    audit_log: Option<&'static AuditLog>,
+    server_sender: UnboundedSender<ServerPacket>,
+    // Dangerous commands waiting to be confirmed, by the ID of the interaction that asked for them
+    pending: Mutex<HashMap<u64, PendingCommand>>,
+}
+
+/// Who took an action from Discord, and where.
+struct Actor {
+    user: User,
+    guild: Option<GuildId>,
+    channel: ChannelId,
+}
+
+impl Actor {
+    fn new(command: &interaction::application_command::ApplicationCommandInteraction) -> Self {
+        Actor {
+            user: command.user.clone(),
+            guild: command.guild_id,
+            channel: command.channel_id,
+        }
+    }
+}
+
+/// The servers a command from Discord is for.
+enum Target {
+    Server(String),
+    Group(String),
+    All,
+}
+
+impl Target {
+    fn from_server(server: Option<String>) -> Self {
+        match server {
+            Some(server) => Target::Server(server),
+            None => Target::All,
+        }
+    }
+}
+
+struct PendingCommand {
+    actor: Actor,
+    roles: Vec<u64>,
+    target: Target,
+    command: String,
+}
+
+impl Handler {
+    pub fn new(
+        config: &'static LiveConfig,
+        bot: Option<&'static str>,
+        server: &'static Server,
+        scheduler: &'static Scheduler,
+        audit_log: Option<&'static AuditLog>,
+        server_sender: UnboundedSender<ServerPacket>,
+    ) -> Self {
+        Handler {
+            config,
+            bot,
+            server,
+            scheduler,
+            audit_log,
+            server_sender,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        info!("Connected to Discord as {}", ready.user.name);
+
+        // Guild commands show up straight away, but global ones can take an hour
+        let guilds = &self.config.get().guilds;
+        if guilds.is_empty() {
+            debug!("Registering global commands...");
+            let res =
+                command::Command::set_global_application_commands(&ctx.http, create_commands).await;
+            if let Err(err) = res {
+                error!("Failed to register global commands: {}", err);
+            }
+        } else {
+            for guild in guilds {
+                // Other bots can be in guilds this one isn't in
+                if !ready
+                    .guilds
+                    .iter()
+                    .any(|ready_guild| ready_guild.id.0 == guild.id)
+                {
+                    continue;
+                }
+                debug!("Registering commands in guild {}...", guild.id);
+                let res = GuildId(guild.id)
+                    .set_application_commands(&ctx.http, create_commands)
+                    .await;
+                if let Err(err) = res {
+                    error!("Failed to register commands in guild {}: {}", guild.id, err);
+                }
+            }
+
+            // Commands registered globally before guilds were configured would show up twice
+            debug!("Removing global commands...");
+            let res =
+                command::Command::set_global_application_commands(&ctx.http, |commands| commands)
+                    .await;
+            if let Err(err) = res {
+                error!("Failed to remove global commands: {}", err);
+            }
+        }
+
+        debug!("😎");
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: interaction::Interaction) {
+        let command = match interaction {
+            interaction::Interaction::ApplicationCommand(command) => command,
+            interaction::Interaction::MessageComponent(component) => {
+                self.confirm(&ctx, &component).await;
+                return;
+            }
+            interaction::Interaction::Autocomplete(autocomplete) => {
+                self.autocomplete(&ctx, &autocomplete).await;
+                return;
+            }
+            _ => return,
+        };
+
+        let roles: Vec<u64> = command
+            .member
+            .as_ref()
+            .map(|member| member.roles.iter().map(|role| role.0).collect())
+            .unwrap_or_default();
+        let user = DiscordUser {
+            id: command.user.id.0,
+            roles: &roles,
+        };
+
+        match command.data.name.as_str() {
+            "exec" => {
+                let cmd = string_option(&command.data.options, "command").unwrap_or_default();
+                match self.option_target(&command) {
+                    Ok(target) => self.exec(&ctx, &command, &user, target, cmd).await,
+                    Err(err) => command
+                        .create_interaction_response(&ctx.http, |r| interaction_error(r, err))
+                        .await
+                        .unwrap(),
+                }
+            }
+            "execall" => {
+                let cmd = string_option(&command.data.options, "command").unwrap_or_default();
+                self.exec(&ctx, &command, &user, Target::All, cmd).await;
+            }
+            "announce" => {
+                let message = string_option(&command.data.options, "message").unwrap_or_default();
+                let target = match self.option_target(&command) {
+                    Ok(target) => target,
+                    Err(err) => {
+                        command
+                            .create_interaction_response(&ctx.http, |r| interaction_error(r, err))
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                };
+
+                let res = match self.check_target(&target, command.guild_id).await {
+                    Ok(()) => self.check_announce_on(&user, &target, command.guild_id),
+                    Err(err) => Err(err),
+                };
+                if res.is_ok() {
+                    let event = ServerEvent::Chat {
+                        message: message.to_string(),
+                    };
+                    self.send(&target, command.guild_id, event);
+                }
+                match &res {
+                    Ok(()) => command
+                        .create_interaction_response(&ctx.http, |r| {
+                            r.interaction_response_data(|data| {
+                                data.ephemeral(true).content("Sent.")
+                            })
+                        })
+                        .await
+                        .unwrap(),
+                    Err(err) => command
+                        .create_interaction_response(&ctx.http, |r| interaction_error(r, err))
+                        .await
+                        .unwrap(),
+                }
+
+                let action = AuditAction::Announce {
+                    message: message.to_string(),
+                };
+                self.audit(&ctx, &Actor::new(&command), &target, action, &res)
+                    .await;
+            }
+            "status" => {
+                let status = self.status(command.guild_id).await;
+                command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|data| {
+                            data.ephemeral(true)
+                                .embed(|embed| embed.title("Status").description(status))
+                        })
+                    })
+                    .await
+                    .unwrap();
+            }
+            "schedule" => match self
+                .schedule(&ctx, &Actor::new(&command), &user, &command.data.options[0])
+                .await
+            {
+                Ok(description) => {
+                    command
+                        .create_interaction_response(&ctx.http, |r| {
+                            r.interaction_response_data(|data| {
+                                data.ephemeral(true).embed(|embed| {
+                                    embed.title("Schedules").description(description)
+                                })
+                            })
+                        })
+                        .await
+                        .unwrap();
+                }
+                Err(err) => {
+                    command
+                        .create_interaction_response(&ctx.http, |r| {
+                            interaction_error(r, &err.to_string())
+                        })
+                        .await
+                        .unwrap();
+                }
+            },
+            "audit" => match self.recent_audit(&user, command.guild_id, &command.data.options) {
+                Ok(description) => {
+                    command
+                        .create_interaction_response(&ctx.http, |r| {
+                            r.interaction_response_data(|data| {
+                                data.ephemeral(true).embed(|embed| {
+                                    embed.title("Audit log").description(description)
+                                })
+                            })
+                        })
+                        .await
+                        .unwrap();
+                }
+                Err(err) => {
+                    command
+                        .create_interaction_response(&ctx.http, |r| {
+                            interaction_error(r, &err.to_string())
+                        })
+                        .await
+                        .unwrap();
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Handler {
+    /// Executes a command for `/exec` or `/execall`, if the user is allowed to. Dangerous commands are only executed
+    /// once the user confirms them.
+    async fn exec(
+        &self,
+        ctx: &Context,
+        command: &interaction::application_command::ApplicationCommandInteraction,
+        user: &DiscordUser<'_>,
+        target: Target,
+        cmd: &str,
+    ) {
+        let config = self.config.get();
+        let actor = Actor::new(command);
+        let res = match self.check_target(&target, actor.guild).await {
+            Ok(()) => self.check_execute_on(user, &target, actor.guild, cmd),
+            Err(err) => Err(err),
+        };
+
+        if res.is_ok() && needs_confirmation(config, cmd) {
+            let question = format!(
+                "Execute `{}` on {}?",
+                cmd,
+                self.describe_target(&target, actor.guild).await
+            );
+            let id = command.id.0;
+            self.pending.lock().unwrap().insert(
+                id,
+                PendingCommand {
+                    actor,
+                    roles: user.roles.to_vec(),
+                    target,
+                    command: cmd.to_string(),
+                },
+            );
+            command
+                .create_interaction_response(&ctx.http, |r| interaction_confirm(r, id, &question))
+                .await
+                .unwrap();
+
+            tokio::time::sleep(config.confirm.timeout()).await;
+            // Still pending if neither button was pressed
+            let pending = self.pending.lock().unwrap().remove(&id);
+            if let Some(pending) = pending {
+                let res = command
+                    .edit_original_interaction_response(&ctx.http, |r| {
+                        r.content("Timed out.").components(|components| components)
+                    })
+                    .await;
+                if let Err(err) = res {
+                    error!("Failed to update confirmation: {}", err);
+                }
+
+                let action = AuditAction::Exec {
+                    command: pending.command,
+                };
+                let res = Err::<(), _>("timed out");
+                self.audit(ctx, &pending.actor, &pending.target, action, &res)
+                    .await;
+            }
+            return;
+        }
+
+        if res.is_ok() {
+            self.send_exec(&target, actor.guild, cmd);
+        }
+        match &res {
+            Ok(()) => command
+                .create_interaction_response(&ctx.http, |r| interaction_command(r, cmd))
+                .await
+                .unwrap(),
+            Err(err) => command
+                .create_interaction_response(&ctx.http, |r| interaction_error(r, err))
+                .await
+                .unwrap(),
+        }
+        if res.is_ok() {
+            self.echo_exec(ctx, &actor, &target, cmd).await;
+        }
+
+        let action = AuditAction::Exec {
+            command: cmd.to_string(),
+        };
+        self.audit(ctx, &actor, &target, action, &res).await;
+    }
+
+    /// Executes or cancels a dangerous command when one of its buttons is pressed.
+    async fn confirm(
+        &self,
+        ctx: &Context,
+        component: &interaction::message_component::MessageComponentInteraction,
+    ) {
+        let Some((choice, id)) = component.data.custom_id.split_once(':') else {
+            return;
+        };
+        let Ok(id) = id.parse::<u64>() else { return };
+
+        let pending = self.pending.lock().unwrap().remove(&id);
+        let Some(pending) = pending else {
+            let res = component
+                .create_interaction_response(&ctx.http, |r| {
+                    interaction_update(r, "This has timed out.")
+                })
+                .await;
+            if let Err(err) = res {
+                error!("Failed to update confirmation: {}", err);
+            }
+            return;
+        };
+
+        let (res, content) = match choice {
+            "confirm" => {
+                // The config could have been reloaded, or the server could have disconnected, while waiting
+                let user = DiscordUser {
+                    id: pending.actor.user.id.0,
+                    roles: &pending.roles,
+                };
+                let res = match self
+                    .check_target(&pending.target, pending.actor.guild)
+                    .await
+                {
+                    Ok(()) => self.check_execute_on(
+                        &user,
+                        &pending.target,
+                        pending.actor.guild,
+                        &pending.command,
+                    ),
+                    Err(err) => Err(err),
+                };
+                let content = match &res {
+                    Ok(()) => format!("```{}```", pending.command),
+                    Err(err) => format!("Error: {}", err),
+                };
+                (res, content)
+            }
+            _ => (Err("cancelled".to_string()), "Cancelled.".to_string()),
+        };
+
+        if res.is_ok() {
+            self.send_exec(&pending.target, pending.actor.guild, &pending.command);
+        }
+        let update = component
+            .create_interaction_response(&ctx.http, |r| interaction_update(r, &content))
+            .await;
+        if let Err(err) = update {
+            error!("Failed to update confirmation: {}", err);
+        }
+        if res.is_ok() {
+            self.echo_exec(ctx, &pending.actor, &pending.target, &pending.command)
+                .await;
+        }
+
+        let action = AuditAction::Exec {
+            command: pending.command,
+        };
+        self.audit(ctx, &pending.actor, &pending.target, action, &res)
+            .await;
+    }
+
+    /// Suggests server or group names for the option being typed.
+    async fn autocomplete(
+        &self,
+        ctx: &Context,
+        autocomplete: &interaction::autocomplete::AutocompleteInteraction,
+    ) {
+        let Some(option) = focused_option(&autocomplete.data.options) else {
+            return;
+        };
+        let typed = option
+            .value
+            .as_ref()
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let guild = autocomplete.guild_id;
+        let mut names = match option.name.as_str() {
+            "server" => {
+                let mut names = self.server_names().await;
+                names.retain(|server| self.controls(guild, server));
+                names
+            }
+            "group" => {
+                let config = self.config.get();
+                let mut names = config.group_names();
+                names.retain(|group| {
+                    let servers = config.group(group).unwrap_or_default();
+                    servers.iter().all(|server| self.controls(guild, server))
+                });
+                names
+            }
+            _ => Vec::new(),
+        };
+        names.retain(|name| name.to_lowercase().contains(&typed));
+        names.sort_unstable();
+        names.truncate(MAX_AUTOCOMPLETE_CHOICES);
+
+        let res = autocomplete
+            .create_autocomplete_response(&ctx.http, |response| {
+                for name in &names {
+                    response.add_string_choice(name, name);
+                }
+                response
+            })
+            .await;
+        if let Err(err) = res {
+            error!("Failed to send autocomplete choices: {}", err);
+        }
+    }
+
+    /// Every configured or connected server's name, without duplicates.
+    async fn server_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.config.get().servers.keys().cloned().collect();
+        for connection in self.server.connections().await {
+            if let Some(name) = connection.name {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Whether this bot owns a server, and commands from a guild can control it.
+    fn controls(&self, guild: Option<GuildId>, server: &str) -> bool {
+        let config = self.config.get();
+        config.bot(server) == self.bot && guild_controls(config, guild.map(|guild| guild.0), server)
+    }
+
+    /// Finds the target of a command from its `server` and `group` options, or the server linked to the channel it
+    /// was sent in if it has neither.
+    fn option_target(
+        &self,
+        command: &interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<Target, &'static str> {
+        let options = &command.data.options;
+        match (
+            string_option(options, "server"),
+            string_option(options, "group"),
+        ) {
+            (Some(server), None) => Ok(Target::Server(server.to_string())),
+            (None, Some(group)) => Ok(Target::Group(group.to_string())),
+            (None, None) => self
+                .config
+                .get()
+                .servers
+                .iter()
+                .find(|(_, config)| config.channel == Some(command.channel_id.0))
+                .map(|(name, _)| Target::Server(name.clone()))
+                .ok_or("not in a linked channel, so give a server or group"),
+            (Some(_), Some(_)) => Err("give either a server or a group"),
+        }
+    }
+
+    /// Checks that a target's server or group exists, and that the guild a command came from can control it.
+    async fn check_target(&self, target: &Target, guild: Option<GuildId>) -> Result<(), String> {
+        match target {
+            Target::Server(server) => {
+                if !self.server_names().await.contains(server) {
+                    return Err(format!("unknown server \"{}\"", server));
+                }
+                if !self.controls(guild, server) {
+                    return Err(format!("**{}** can't be controlled from here", server));
+                }
+            }
+            Target::Group(group) => match self.config.get().group(group) {
+                Some(servers) if servers.is_empty() => {
+                    return Err(format!("group \"{}\" has no servers", group))
+                }
+                Some(servers) => {
+                    if let Some(server) =
+                        servers.iter().find(|server| !self.controls(guild, server))
+                    {
+                        return Err(format!(
+                            "**{}** in group \"{}\" can't be controlled from here",
+                            server, group
+                        ));
+                    }
+                }
+                None => return Err(format!("unknown group \"{}\"", group)),
+            },
+            Target::All => match self.target_servers(target, guild) {
+                Some(servers) if servers.is_empty() => {
+                    return Err("no servers can be controlled from here".to_string())
+                }
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    /// Checks whether a user can execute a command on every server in a target.
+    fn check_execute_on(
+        &self,
+        user: &DiscordUser,
+        target: &Target,
+        guild: Option<GuildId>,
+        cmd: &str,
+    ) -> Result<(), String> {
+        let config = self.config.get();
+        self.check_each_server(target, guild, |server| {
+            check_execute(config, user, server, cmd)
+        })
+    }
+
+    /// Checks whether a user can announce on every server in a target.
+    fn check_announce_on(
+        &self,
+        user: &DiscordUser,
+        target: &Target,
+        guild: Option<GuildId>,
+    ) -> Result<(), String> {
+        let config = self.config.get();
+        self.check_each_server(target, guild, |server| check_announce(config, user, server))
+    }
+
+    /// Runs a check for each server in a target, or once with `None` for every server.
+    fn check_each_server(
+        &self,
+        target: &Target,
+        guild: Option<GuildId>,
+        check: impl Fn(Option<&str>) -> Result<(), String>,
+    ) -> Result<(), String> {
+        match self.target_servers(target, guild) {
+            Some(servers) => {
+                for server in servers {
+                    check(Some(&server))?;
+                }
+                Ok(())
+            }
+            None => check(None),
+        }
+    }
+
+    /// The servers in a target, or `None` for every server. When guilds or more bots are configured, every server means
+    /// every configured server this bot and the guild can control.
+    fn target_servers(&self, target: &Target, guild: Option<GuildId>) -> Option<Vec<String>> {
+        let config = self.config.get();
+        match target {
+            Target::Server(server) => Some(vec![server.clone()]),
+            Target::Group(group) => Some(config.group(group).unwrap_or_default()),
+            Target::All if config.guilds.is_empty() && config.bots.is_empty() => None,
+            Target::All => {
+                let mut servers: Vec<String> = config
+                    .servers
+                    .keys()
+                    .filter(|server| self.controls(guild, server))
+                    .cloned()
+                    .collect();
+                servers.sort_unstable();
+                Some(servers)
+            }
+        }
+    }
+
+    fn send(&self, target: &Target, guild: Option<GuildId>, event: ServerEvent) {
+        let target = match self.target_servers(target, guild) {
+            Some(servers) => forge_shared::Target::Names(servers),
+            None => forge_shared::Target::All,
+        };
+        self.server_sender
+            .send(ServerPacket { target, event })
+            .expect("Failed to send server packet");
+    }
+
+    fn send_exec(&self, target: &Target, guild: Option<GuildId>, cmd: &str) {
+        self.send(
+            target,
+            guild,
+            ServerEvent::ExecCommand {
+                command: cmd.to_string(),
+            },
+        );
+    }
+
+    /// Posts a command executed from Discord in the channels the target's servers display command output in.
+    async fn echo_exec(&self, ctx: &Context, actor: &Actor, target: &Target, cmd: &str) {
+        let channels = self.category_channels(target, actor.guild, Category::CommandOutput);
+        if channels.is_empty() {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let message = Message::Event {
+            category: Category::CommandOutput,
+            description: format!(
+                "<@{}> executed `{}` on {}.",
+                actor.user.id,
+                cmd,
+                self.describe_target(target, actor.guild).await
+            ),
+            timestamp,
+        };
+        for channel in channels {
+            if let Err(err) = post(&ctx.http, channel, &message).await {
+                error!("Failed to post command output in {}: {}", channel, err);
+            }
+        }
+    }
+
+    /// The channels the servers in a target display a category of messages in, without duplicates.
+    fn category_channels(
+        &self,
+        target: &Target,
+        guild: Option<GuildId>,
+        category: Category,
+    ) -> Vec<ChannelId> {
+        let config = self.config.get();
+        let servers = self
+            .target_servers(target, guild)
+            .unwrap_or_else(|| config.servers.keys().cloned().collect());
+        let mut channels: Vec<u64> = servers
+            .iter()
+            .filter_map(|server| config.channel(server, category))
+            .collect();
+        channels.sort_unstable();
+        channels.dedup();
+        channels.into_iter().map(ChannelId).collect()
+    }
+
+    /// Describes the servers a command would be executed on, with Markdown.
+    async fn describe_target(&self, target: &Target, guild: Option<GuildId>) -> String {
+        let (description, mut names) = match (target, self.target_servers(target, guild)) {
+            (Target::Server(server), _) => return format!("**{}**", server),
+            (Target::Group(group), servers) => {
+                let names = servers
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|server| format!("**{}**", server))
+                    .collect();
+                (format!("group **{}**", group), names)
+            }
+            (Target::All, Some(servers)) => {
+                let names = servers
+                    .iter()
+                    .map(|server| format!("**{}**", server))
+                    .collect();
+                ("every server controlled from here".to_string(), names)
+            }
+            (Target::All, None) => {
+                let connections = self.server.connections().await;
+                let servers = connections
+                    .into_iter()
+                    .filter_map(|connection| connection.name);
+                let names = servers.map(|server| format!("**{}**", server)).collect();
+                ("every server".to_string(), names)
+            }
+        };
+
+        names.sort_unstable();
+        names.dedup();
+        if names.is_empty() {
+            return format!("{} (none are connected)", description);
+        }
+        format!("{} ({})", description, names.join(", "))
+    }
+
+    async fn schedule(
+        &self,
+        ctx: &Context,
+        actor: &Actor,
+        user: &DiscordUser<'_>,
+        subcommand: &interaction::application_command::CommandDataOption,
+    ) -> Result<String> {
+        let option = |name: &str| {
+            subcommand
+                .options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.resolved.as_ref())
+        };
+        let string = |name: &str| match option(name) {
+            Some(interaction::application_command::CommandDataOptionValue::String(val)) => {
+                Some(val.clone())
+            }
+            _ => None,
+        };
+        let integer = |name: &str| match option(name) {
+            Some(interaction::application_command::CommandDataOptionValue::Integer(val)) => {
+                Some(*val)
+            }
+            _ => None,
+        };
+
+        match subcommand.name.as_str() {
+            "list" => Ok(fit_description(
+                &self.scheduler.describe(actor.guild.map(|guild| guild.0)),
+            )),
+            "add" => {
+                let when = match (string("cron"), integer("interval")) {
+                    (Some(expression), None) => ScheduleTime::Cron(expression),
+                    (None, Some(minutes)) if minutes > 0 => {
+                        ScheduleTime::IntervalMinutes(minutes as u64)
+                    }
+                    _ => bail!("give either a cron expression or an interval"),
+                };
+                let text = string("text").unwrap_or_default();
+                let action = match string("action").as_deref() {
+                    Some("announce") => ScheduleAction::Announce(text),
+                    _ => ScheduleAction::Exec(text),
+                };
+                let server = string("server");
+                let schedule = ScheduleConfig { when, action };
+
+                let res = self.add_schedule(user, actor.guild, server.clone(), schedule.clone());
+                let action = AuditAction::AddSchedule { schedule };
+                self.audit(ctx, actor, &Target::from_server(server), action, &res)
+                    .await;
+                Ok(format!("Added schedule **#{}**.", res?))
+            }
+            "remove" => {
+                let id = integer("id").unwrap_or_default() as u64;
+                let res = match self.scheduler.get(id) {
+                    Some(schedule) => self
+                        .check_schedule(
+                            user,
+                            actor.guild,
+                            schedule.server.as_deref(),
+                            &schedule.schedule,
+                        )
+                        .and_then(|()| self.scheduler.remove(id)),
+                    None => self.scheduler.remove(id),
+                };
+                let action = AuditAction::RemoveSchedule { id };
+                self.audit(ctx, actor, &Target::All, action, &res).await;
+                res?;
+                Ok(format!("Removed schedule **#{}**.", id))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn add_schedule(
+        &self,
+        user: &DiscordUser,
+        guild: Option<GuildId>,
+        server: Option<String>,
+        schedule: ScheduleConfig,
+    ) -> Result<u64> {
+        self.check_schedule(user, guild, server.as_deref(), &schedule)?;
+        self.scheduler.add(server, schedule)
+    }
+
+    /// Checks that a user can manage a schedule, which needs the same permission as doing what it does.
+    fn check_schedule(
+        &self,
+        user: &DiscordUser,
+        guild: Option<GuildId>,
+        server: Option<&str>,
+        schedule: &ScheduleConfig,
+    ) -> Result<()> {
+        self.check_schedule_server(guild, server)?;
+
+        let config = self.config.get();
+        match &schedule.action {
+            ScheduleAction::Exec(cmd) => check_execute(config, user, server, cmd),
+            ScheduleAction::Announce(_) => check_announce(config, user, server),
+        }
+        .map_err(|err| anyhow!(err))
+    }
+
+    /// Checks that a guild can control the server a schedule is for, or every configured server if it's for every
+    /// server.
+    fn check_schedule_server(&self, guild: Option<GuildId>, server: Option<&str>) -> Result<()> {
+        match server {
+            Some(server) => {
+                if !self.controls(guild, server) {
+                    bail!("**{}** can't be controlled from here", server);
+                }
+            }
+            None => {
+                let servers = &self.config.get().servers;
+                if !servers.keys().all(|server| self.controls(guild, server)) {
+                    bail!("schedules for every server can't be managed from here");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an action to the audit log, if there is one, and posts it in the channels the target's servers display
+    /// moderation in. Actions on no configured servers, like ones refused for an unknown server, are posted in the
+    /// audit channel.
+    async fn audit<T, E: Display>(
+        &self,
+        ctx: &Context,
+        actor: &Actor,
+        target: &Target,
+        action: AuditAction,
+        res: &Result<T, E>,
+    ) {
+        let outcome = match res {
+            Ok(_) => AuditOutcome::Succeeded,
+            Err(err) => AuditOutcome::Failed {
+                reason: err.to_string(),
+            },
+        };
+        let server = match target {
+            Target::Server(server) => Some(server.clone()),
+            _ => None,
+        };
+        let mut entry = AuditEntry::new(
+            actor.user.id.0,
+            actor.user.tag(),
+            actor.guild.map(|guild| guild.0),
+            actor.channel.0,
+            server,
+            action,
+            outcome,
+        );
+        if let Target::Group(group) = target {
+            entry.group = Some(group.clone());
+        }
+
+        if let Some(audit_log) = self.audit_log {
+            audit_log.write(&entry);
+        }
+
+        let config = self.config.get();
+        let is_configured = match self.target_servers(target, actor.guild) {
+            Some(servers) => servers
+                .iter()
+                .any(|server| config.servers.contains_key(server)),
+            None => !config.servers.is_empty(),
+        };
+        let channels = if is_configured {
+            self.category_channels(target, actor.guild, Category::Moderation)
+        } else {
+            let audit_channel = config.audit.as_ref().and_then(|audit| audit.channel);
+            audit_channel.into_iter().map(ChannelId).collect()
+        };
+
+        let description = entry.describe();
+        for channel in channels {
+            let res = channel
+                .send_message(&ctx.http, |m| {
+                    m.embed(|embed| match &entry.outcome {
+                        AuditOutcome::Succeeded => embed.description(&description),
+                        AuditOutcome::Failed { .. } => {
+                            embed.color(Color::new(0xFF0000)).description(&description)
+                        }
+                    })
+                })
+                .await;
+            if let Err(err) = res {
+                error!("Failed to post to moderation channel {}: {}", channel, err);
+            }
+        }
+    }
+
+    /// Describes the most recent actions in the audit log, out of those on servers the user can see the actions of and
+    /// the guild can control.
+    fn recent_audit(
+        &self,
+        user: &DiscordUser,
+        guild: Option<GuildId>,
+        options: &[interaction::application_command::CommandDataOption],
+    ) -> Result<String> {
+        let Some(audit_log) = self.audit_log else {
+            bail!("there's no audit log file");
+        };
+
+        let mut by_user = None;
+        let mut server = None;
+        let mut count = DEFAULT_AUDIT_COUNT;
+        for option in options {
+            match (option.name.as_str(), option.resolved.as_ref()) {
+                (
+                    "user",
+                    Some(interaction::application_command::CommandDataOptionValue::User(val, _)),
+                ) => by_user = Some(val.id.0),
+                (
+                    "server",
+                    Some(interaction::application_command::CommandDataOptionValue::String(val)),
+                ) => server = Some(val.as_str()),
+                (
+                    "count",
+                    Some(interaction::application_command::CommandDataOptionValue::Integer(val)),
+                ) => count = *val,
+                _ => {}
+            }
+        }
+
+        let config = self.config.get();
+        let can_see = |server: &str| {
+            self.controls(guild, server) && check_audit(config, user, Some(server)).is_ok()
+        };
+        match server {
+            Some(server) => {
+                if !self.controls(guild, server) {
+                    bail!("**{}** can't be controlled from here", server);
+                }
+                check_audit(config, user, Some(server)).map_err(|err| anyhow!(err))?;
+            }
+            None => {
+                if !config.servers.keys().any(|server| can_see(server.as_str())) {
+                    bail!("you aren't allowed to see the audit log of any server here");
+                }
+            }
+        }
+
+        let visible = |entry: &AuditEntry| {
+            let servers = match (&entry.server, &entry.group) {
+                (Some(server), _) => vec![server.clone()],
+                (None, Some(group)) => config.group(group).unwrap_or_default(),
+                // Every server the guild it came from could control
+                (None, None) => config
+                    .servers
+                    .keys()
+                    .filter(|server| guild_controls(config, entry.guild, server))
+                    .cloned()
+                    .collect(),
+            };
+            // Actions for a group that's since been removed can't be checked
+            !servers.is_empty() && servers.iter().all(|server| can_see(server.as_str()))
+        };
+        let count = count.clamp(1, MAX_AUDIT_COUNT) as usize;
+        let entries = audit_log.recent(by_user, server, visible, count)?;
+        if entries.is_empty() {
+            return Ok("No actions.".to_string());
+        }
+
+        // The oldest entries are left out if they don't all fit
+        let mut lines = Vec::new();
+        let mut length = 0;
+        for entry in entries.iter().rev() {
+            let line = entry.describe();
+            length += line.chars().count() + 1;
+            if length > EMBED_DESCRIPTION_LIMIT {
+                break;
+            }
+            lines.push(line);
+        }
+        lines.reverse();
+        Ok(lines.join("\n"))
+    }
+
+    /// Describes the connection of every server the guild can control.
+    async fn status(&self, guild: Option<GuildId>) -> String {
+        let connections = self.server.connections().await;
+
+        let mut names: Vec<&str> = self
+            .config
+            .get()
+            .servers
+            .keys()
+            .map(|name| name as &str)
+            .collect();
+        for connection in &connections {
+            if let Some(name) = &connection.name {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+        names.retain(|name| self.controls(guild, name));
+        names.sort_unstable();
+
+        let mut lines: Vec<String> = names
+            .into_iter()
+            .map(|name| {
+                let connection = connections
+                    .iter()
+                    .find(|connection| connection.name.as_deref() == Some(name));
+                match connection {
+                    Some(connection) => match connection.latency {
+                        Some(latency) => {
+                            format!("**{name}**: connected, {}ms", latency.as_millis())
+                        }
+                        None => format!("**{name}**: connected"),
+                    },
+                    None => format!("**{name}**: not connected"),
+                }
+            })
+            .collect();
+
+        let unidentified = connections
+            .iter()
+            .filter(|connection| connection.name.is_none())
+            .count();
+        if unidentified > 0 {
+            lines.push(format!("{unidentified} unidentified connection(s)"));
+        }
+        if lines.is_empty() {
+            lines.push("No servers are configured.".to_string());
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Adds every command to a set of commands to register.
+fn create_commands(
+    commands: &mut serenity::builder::CreateApplicationCommands,
+) -> &mut serenity::builder::CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name("exec")
+                .description("Execute a command on a server.")
+                .create_option(|option| {
+                    option
+                        .name("command")
+                        .description("A command to execute.")
+                        .kind(command::CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("server")
+                        .description("The server to execute it on, instead of the one linked to this channel.")
+                        .kind(command::CommandOptionType::String)
+                        .set_autocomplete(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("group")
+                        .description("A group of servers to execute it on.")
+                        .kind(command::CommandOptionType::String)
+                        .set_autocomplete(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("execall")
+                .description("Execute a command on all servers.")
+                .create_option(|option| {
+                    option
+                        .name("command")
+                        .description("A command to execute.")
+                        .kind(command::CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("announce")
+                .description("Show a message in the chat of a server.")
+                .create_option(|option| {
+                    option
+                        .name("message")
+                        .description("The message to show.")
+                        .kind(command::CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("server")
+                        .description("The server to show it on, instead of the one linked to this channel.")
+                        .kind(command::CommandOptionType::String)
+                        .set_autocomplete(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("group")
+                        .description("A group of servers to show it on.")
+                        .kind(command::CommandOptionType::String)
+                        .set_autocomplete(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("status")
+                .description("Show which servers are connected.")
+        })
+        .create_application_command(|command| {
+            command
+                .name("schedule")
+                .description("Manage commands and announcements that happen at certain times.")
+                .create_option(|option| {
+                    option
+                        .name("list")
+                        .description("Show every schedule.")
+                        .kind(command::CommandOptionType::SubCommand)
+                })
+                .create_option(|option| {
+                    option
+                        .name("add")
+                        .description("Add a schedule.")
+                        .kind(command::CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("action")
+                                .description("What to do.")
+                                .kind(command::CommandOptionType::String)
+                                .add_string_choice("Execute a command", "exec")
+                                .add_string_choice("Announce in chat", "announce")
+                                .required(true)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("text")
+                                .description("The command to execute or message to announce.")
+                                .kind(command::CommandOptionType::String)
+                                .required(true)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("cron")
+                                .description("When to do it, as a cron expression in UTC starting with the seconds.")
+                                .kind(command::CommandOptionType::String)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("interval")
+                                .description("How often to do it, in minutes.")
+                                .kind(command::CommandOptionType::Integer)
+                                .min_int_value(1)
+                        })
+                        .create_sub_option(|option| {
+                            option
+                                .name("server")
+                                .description("The server to do it on, instead of every server.")
+                                .kind(command::CommandOptionType::String)
+                                .set_autocomplete(true)
+                        })
+                })
+                .create_option(|option| {
+                    option
+                        .name("remove")
+                        .description("Remove a schedule added with this command.")
+                        .kind(command::CommandOptionType::SubCommand)
+                        .create_sub_option(|option| {
+                            option
+                                .name("id")
+                                .description("The number of the schedule, as shown in the list.")
+                                .kind(command::CommandOptionType::Integer)
+                                .required(true)
+                        })
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("audit")
+                .description("Show recent administrative actions.")
+                .create_option(|option| {
+                    option
+                        .name("user")
+                        .description("Only show actions by this user.")
+                        .kind(command::CommandOptionType::User)
+                })
+                .create_option(|option| {
+                    option
+                        .name("server")
+                        .description("Only show actions for this server.")
+                        .kind(command::CommandOptionType::String)
+                        .set_autocomplete(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("count")
+                        .description("How many actions to show.")
+                        .kind(command::CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(MAX_AUDIT_COUNT)
+                })
+        })
+}
+
+fn string_option<'a>(
+    options: &'a [interaction::application_command::CommandDataOption],
+    name: &str,
+) -> Option<&'a str> {
+    let option = options.iter().find(|option| option.name == name)?;
+    match option.resolved.as_ref()? {
+        interaction::application_command::CommandDataOptionValue::String(val) => Some(val),
+        _ => None,
+    }
+}
+
+/// Finds the option being typed, which can be in a subcommand.
+fn focused_option(
+    options: &[interaction::application_command::CommandDataOption],
+) -> Option<&interaction::application_command::CommandDataOption> {
+    options.iter().find_map(|option| {
+        if option.focused {
+            Some(option)
+        } else {
+            focused_option(&option.options)
+        }
+    })
+}
+
+fn interaction_error<'a, 'b>(
+    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
+    err: &str,
+) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
+    let err_str = format!("Error: {}", err);
+    response.interaction_response_data(|data| {
+        data.ephemeral(true)
+            .embed(|embed| embed.color(Color::new(0xFF0000)).description(err_str))
+    })
+}
+
+fn interaction_confirm<'a, 'b>(
+    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
+    id: u64,
+    question: &str,
+) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
+    response.interaction_response_data(|data| {
+        data.ephemeral(true)
+            .content(question)
+            .components(|components| {
+                components.create_action_row(|row| {
+                    row.create_button(|button| {
+                        button
+                            .custom_id(format!("confirm:{}", id))
+                            .label("Confirm")
+                            .style(component::ButtonStyle::Danger)
+                    })
+                    .create_button(|button| {
+                        button
+                            .custom_id(format!("cancel:{}", id))
+                            .label("Cancel")
+                            .style(component::ButtonStyle::Secondary)
+                    })
+                })
+            })
+    })
+}
+
+/// Replaces the message a button was on, removing the buttons.
+fn interaction_update<'a, 'b>(
+    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
+    content: &str,
+) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
+    response
+        .kind(interaction::InteractionResponseType::UpdateMessage)
+        .interaction_response_data(|data| data.content(content).components(|components| components))
+}
+
+fn interaction_command<'a, 'b>(
+    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
+    cmd: &str,
+) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
+    let str = format!("```{}```", cmd);
+    response.interaction_response_data(|data| data.ephemeral(true).content(str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> Message {
+        Message::Chat {
+            text: text.to_string(),
+            sent_at: None,
+        }
+    }
+
+    fn event(category: Category, description: &str) -> Message {
+        Message::Event {
+            category,
+            description: description.to_string(),
+            timestamp: 1000,
+        }
+    }
+
+    fn queued(messages: impl IntoIterator<Item = Message>) -> ChannelQueue {
+        let queue = ChannelQueue::default();
+        for message in messages {
+            queue.push(message);
+        }
+        queue
+    }
+
+    #[test]
+    fn batches_runs_of_the_same_kind() {
+        let queue = queued([
+            chat("a"),
+            Message::Chat {
+                text: "b".to_string(),
+                sent_at: Some(5000),
+            },
+            event(Category::Joins, "joined"),
+            event(Category::Joins, "left"),
+            chat("c"),
+        ]);
+        assert_eq!(
+            queue.take_batch(),
+            Some(Message::Chat {
+                text: "a\n<t:5:T> b".to_string(),
+                sent_at: None,
+            })
+        );
+        assert_eq!(
+            queue.take_batch(),
+            Some(event(Category::Joins, "joined\nleft"))
+        );
+        assert_eq!(queue.take_batch(), Some(chat("c")));
+        assert_eq!(queue.take_batch(), None);
+    }
+
+    #[test]
+    fn batches_stop_at_other_categories() {
+        let queue = queued([
+            event(Category::Joins, "joined"),
+            event(Category::Matches, "started"),
+            event(Category::Matches, "started again"),
+        ]);
+        assert_eq!(queue.take_batch(), Some(event(Category::Joins, "joined")));
+        assert_eq!(
+            queue.take_batch(),
+            Some(event(Category::Matches, "started\nstarted again"))
+        );
+    }
+
+    #[test]
+    fn batches_fit_in_a_message() {
+        let line = "x".repeat(999);
+        let queue = queued([chat(&line), chat(&line), chat(&line)]);
+        // Two lines and the line break between them are 1999 characters
+        assert_eq!(queue.take_batch(), Some(chat(&format!("{line}\n{line}"))));
+        assert_eq!(queue.take_batch(), Some(chat(&line)));
+
+        let queue = queued([chat(&"y".repeat(2500)), chat("z")]);
+        let Some(Message::Chat { text, .. }) = queue.take_batch() else {
+            panic!("expected a chat message");
+        };
+        assert_eq!(text.chars().count(), MESSAGE_CONTENT_LIMIT);
+        assert!(text.ends_with("y…"));
+        assert_eq!(queue.take_batch(), Some(chat("z")));
+
+        let description = "é".repeat(5000);
+        let queue = queued([event(Category::Joins, &description)]);
+        let Some(Message::Event { description, .. }) = queue.take_batch() else {
+            panic!("expected an event");
+        };
+        assert_eq!(description.chars().count(), EMBED_DESCRIPTION_LIMIT);
+    }
+
+    #[test]
+    fn warns_about_dropped_messages_first() {
+        let queue = queued((0..MAX_QUEUED_MESSAGES + 2).map(|i| chat(&i.to_string())));
+        assert_eq!(
+            queue.take_batch(),
+            Some(Message::Warning {
+                description: "2 message(s) were dropped because Discord couldn't keep up."
+                    .to_string()
+            })
+        );
+        let Some(Message::Chat { text, .. }) = queue.take_batch() else {
+            panic!("expected a chat message");
+        };
+        assert!(text.starts_with("2\n3\n"));
+        assert!(text.ends_with("\n101"));
+        assert_eq!(queue.take_batch(), None);
+    }
+
+    #[test]
+    fn fits_descriptions_by_whole_lines() {
+        assert_eq!(fit_description("a\nb"), "a\nb");
+
+        let line = "x".repeat(99);
+        let text = vec![line.as_str(); 50].join("\n");
+        let description = fit_description(&text);
+        assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+        assert_eq!(description.lines().count(), 41);
+        assert!(description.ends_with(&format!("{}\n…and 10 more.", line)));
+    }
+}
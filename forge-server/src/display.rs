@@ -0,0 +1,271 @@
+use crate::config::{Config, LiveConfig};
+use crate::sink::{Category, Message, Sink};
+use forge_shared::{ClientEvent, ClientPacket};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// Chat displayed later than this after it was sent is shown with the time it was sent.
+const LATE_CHAT_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Turns packets from clients into messages, keeping track of sequence numbers to notice lost events.
+#[derive(Default)]
+pub struct Renderer {
+    last_sequences: HashMap<String, u64>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders the messages to display for a packet, which is empty if it came from an unknown client.
+    pub fn render(&mut self, config: &Config, packet: &ClientPacket) -> Vec<Message> {
+        if !config.servers.contains_key(&packet.name) {
+            warn!(
+                "Event from unknown client \"{}\": {}",
+                packet.name, packet.event
+            );
+            return Vec::new();
+        }
+
+        let mut messages = Vec::new();
+
+        // Sequences start over when the plugin restarts, so only a jump forward is a gap
+        let last_sequence = self
+            .last_sequences
+            .insert(packet.name.clone(), packet.sequence);
+        if let Some(last_sequence) = last_sequence {
+            if packet.sequence > last_sequence + 1 {
+                let lost = packet.sequence - last_sequence - 1;
+                warn!("{} event(s) from \"{}\" were lost", lost, packet.name);
+                messages.push(Message::Warning {
+                    description: format!("{lost} event(s) were lost."),
+                });
+            }
+        }
+
+        let timestamp = packet.timestamp;
+        messages.push(match &packet.event {
+            ClientEvent::GameStart { map, mode } => {
+                let map_en = config
+                    .maps
+                    .get(map)
+                    .cloned()
+                    .unwrap_or_else(|| format!("`{}`", map));
+                let mode_en = config
+                    .modes
+                    .get(mode)
+                    .cloned()
+                    .unwrap_or_else(|| format!("`{}`", mode));
+
+                Message::Event {
+                    category: Category::Matches,
+                    description: format!("Starting **{mode_en}** on **{map_en}**."),
+                    timestamp,
+                }
+            }
+            ClientEvent::ClientConnecting { name, uid } => Message::Event {
+                category: Category::Joins,
+                description: format!("**{name}** (`{uid}`) joined."),
+                timestamp,
+            },
+            ClientEvent::ClientDisconnected { name, uid } => Message::Event {
+                category: Category::Joins,
+                description: format!("**{name}** (`{uid}`) left."),
+                timestamp,
+            },
+            ClientEvent::ClientChat {
+                name,
+                message,
+                is_team,
+                ..
+            } => {
+                let age = SystemTime::now()
+                    .duration_since(UNIX_EPOCH + Duration::from_millis(timestamp))
+                    .unwrap_or_default();
+                Message::Chat {
+                    text: format!(
+                        "{}**{name}**: {message}",
+                        if *is_team { "[TEAM] " } else { "" }
+                    ),
+                    sent_at: (age > LATE_CHAT_THRESHOLD).then_some(timestamp),
+                }
+            }
+        });
+
+        messages
+    }
+}
+
+pub async fn run_client_display_loop(
+    config: &LiveConfig,
+    sink: &dyn Sink,
+    mut client_receiver: UnboundedReceiver<ClientPacket>,
+) {
+    let mut renderer = Renderer::new();
+
+    while let Some(packet) = client_receiver.recv().await {
+        for message in renderer.render(config.get(), &packet) {
+            if let Err(err) = sink.send(&packet.name, &message).await {
+                error!("Failed to display message: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            listen = "127.0.0.1:0"
+            discord-token = ""
+            discord-application = 0
+
+            [servers.test]
+
+            [maps]
+            mp_glitch = "Glitch"
+
+            [modes]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    fn packet(name: &str, sequence: u64, timestamp: u64, event: ClientEvent) -> ClientPacket {
+        ClientPacket {
+            name: name.to_string(),
+            sequence,
+            timestamp,
+            event,
+        }
+    }
+
+    fn join(sequence: u64) -> ClientPacket {
+        packet(
+            "test",
+            sequence,
+            0,
+            ClientEvent::ClientConnecting {
+                name: "player".to_string(),
+                uid: "1".to_string(),
+            },
+        )
+    }
+
+    fn chat(timestamp: u64) -> ClientPacket {
+        packet(
+            "test",
+            1,
+            timestamp,
+            ClientEvent::ClientChat {
+                name: "player".to_string(),
+                uid: "1".to_string(),
+                message: "hello".to_string(),
+                is_team: true,
+            },
+        )
+    }
+
+    #[test]
+    fn renders_events() {
+        let config = config();
+        let mut renderer = Renderer::new();
+
+        let game_start = packet(
+            "test",
+            1,
+            1000,
+            ClientEvent::GameStart {
+                map: "mp_glitch".to_string(),
+                mode: "aitdm".to_string(),
+            },
+        );
+        assert_eq!(
+            renderer.render(&config, &game_start),
+            vec![Message::Event {
+                category: Category::Matches,
+                description: "Starting **`aitdm`** on **Glitch**.".to_string(),
+                timestamp: 1000,
+            }]
+        );
+        assert_eq!(
+            renderer.render(&config, &join(2)),
+            vec![Message::Event {
+                category: Category::Joins,
+                description: "**player** (`1`) joined.".to_string(),
+                timestamp: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_clients() {
+        let mut renderer = Renderer::new();
+        let mut packet = join(1);
+        packet.name = "other".to_string();
+        assert!(renderer.render(&config(), &packet).is_empty());
+    }
+
+    #[test]
+    fn warns_about_lost_events() {
+        let config = config();
+        let mut renderer = Renderer::new();
+        assert_eq!(renderer.render(&config, &join(1)).len(), 1);
+        assert_eq!(renderer.render(&config, &join(2)).len(), 1);
+
+        let messages = renderer.render(&config, &join(5));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            Message::Warning {
+                description: "2 event(s) were lost.".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn restarted_sequences_are_not_gaps() {
+        let config = config();
+        let mut renderer = Renderer::new();
+        renderer.render(&config, &join(5));
+        assert_eq!(renderer.render(&config, &join(1)).len(), 1);
+        assert_eq!(renderer.render(&config, &join(2)).len(), 1);
+    }
+
+    #[test]
+    fn shows_when_late_chat_was_sent() {
+        let config = config();
+        let mut renderer = Renderer::new();
+
+        assert_eq!(
+            renderer.render(&config, &chat(now())),
+            vec![Message::Chat {
+                text: "[TEAM] **player**: hello".to_string(),
+                sent_at: None,
+            }]
+        );
+
+        let sent_at = now() - LATE_CHAT_THRESHOLD.as_millis() as u64 - 1000;
+        let mut renderer = Renderer::new();
+        assert_eq!(
+            renderer.render(&config, &chat(sent_at)),
+            vec![Message::Chat {
+                text: "[TEAM] **player**: hello".to_string(),
+                sent_at: Some(sent_at),
+            }]
+        );
+    }
+}
@@ -0,0 +1,447 @@
+use crate::config::{IrcConfig, LiveConfig};
+use crate::permissions::{check_command, wildcard_matches};
+use crate::sink::{format_time, Message, Sink};
+use anyhow::{anyhow, bail, Result};
+use forge_shared::{ServerEvent, ServerPacket, Target};
+use log::{debug, error, info};
+use serenity::async_trait;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+
+// How long to wait before reconnecting after the connection to the IRC server is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+// Messages are cut to this many bytes, leaving room in the 512 byte line limit for the command and prefix.
+const MAX_MESSAGE_LEN: usize = 400;
+// How long connecting and registering can take before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+// After this long without hearing from the IRC server it's pinged, and after this long again the connection is
+// considered dead. Otherwise a connection that silently dropped would never be noticed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// IRC servers disconnect clients that send too much at once. Lines are sent at most this often, after a burst of up to
+// `MAX_BURST / LINE_INTERVAL` lines, like the flood control in RFC 1459.
+const LINE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_BURST: Duration = Duration::from_secs(10);
+
+/// An IRC client that displays messages in the channel linked to each server, and executes commands sent in those
+/// channels by admins.
+pub struct Irc {
+    config: &'static LiveConfig,
+    irc_config: &'static IrcConfig,
+    write: Mutex<Option<Writer>>,
+}
+
+/// The sending half of the connection to the IRC server.
+struct Writer {
+    write: OwnedWriteHalf,
+    throttle: Throttle,
+}
+
+/// Spaces out lines sent to the IRC server so it doesn't disconnect the bot for flooding.
+struct Throttle {
+    // Moves forward by `LINE_INTERVAL` for each line sent, and is never more than `MAX_BURST` ahead of now
+    timer: Instant,
+}
+
+impl Throttle {
+    fn new(now: Instant) -> Self {
+        Throttle { timer: now }
+    }
+
+    /// Counts a line sent at `now`, returning how long to wait before sending it.
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.timer = self.timer.max(now) + LINE_INTERVAL;
+        self.timer.saturating_duration_since(now + MAX_BURST)
+    }
+}
+
+/// A line received from the IRC server.
+struct IrcMessage<'a> {
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl Irc {
+    pub fn new(config: &'static LiveConfig, irc_config: &'static IrcConfig) -> Self {
+        Irc {
+            config,
+            irc_config,
+            write: Mutex::new(None),
+        }
+    }
+
+    /// Stays connected to the IRC server forever, executing commands as they're received.
+    pub async fn run(&self, server_sender: UnboundedSender<ServerPacket>) {
+        loop {
+            if let Err(err) = self.run_connection(&server_sender).await {
+                error!("IRC connection failed: {}", err);
+            }
+            *self.write.lock().await = None;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn run_connection(&self, server_sender: &UnboundedSender<ServerPacket>) -> Result<()> {
+        let stream =
+            tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.irc_config.server))
+                .await
+                .map_err(|_| anyhow!("timed out connecting"))??;
+        let (read, write) = stream.into_split();
+        *self.write.lock().await = Some(Writer {
+            write,
+            throttle: Throttle::new(Instant::now()),
+        });
+
+        if let Some(password) = &self.irc_config.password {
+            self.send_line(&format!("PASS {}", password)).await?;
+        }
+        let mut nickname = self.irc_config.nickname.clone();
+        self.send_line(&format!("NICK {}", nickname)).await?;
+        self.send_line(&format!("USER {} 0 * :Forge", self.irc_config.nickname))
+            .await?;
+
+        let connected_at = Instant::now();
+        let mut registered = false;
+        let mut pinged = false;
+        let mut lines = BufReader::new(read).lines();
+        loop {
+            if !registered && connected_at.elapsed() > REGISTRATION_TIMEOUT {
+                bail!("timed out registering");
+            }
+            let line = match tokio::time::timeout(IDLE_TIMEOUT, lines.next_line()).await {
+                Ok(line) => line?,
+                Err(_) if !pinged => {
+                    self.send_line("PING :forge").await?;
+                    pinged = true;
+                    continue;
+                }
+                Err(_) => bail!("no response from the IRC server"),
+            };
+            let Some(line) = line else { break };
+            pinged = false;
+
+            let Some(message) = parse_message(&line) else {
+                continue;
+            };
+
+            match (message.command, message.params.as_slice()) {
+                ("PING", params) => {
+                    let token = params.first().copied().unwrap_or_default();
+                    self.send_line(&format!("PONG :{}", token)).await?;
+                }
+                // Welcome, sent once registration is complete
+                ("001", [nickname_given, ..]) => {
+                    registered = true;
+                    nickname = nickname_given.to_string();
+                    info!("Connected to IRC as {}", nickname);
+                    for channel in self.channels() {
+                        self.send_line(&format!("JOIN {}", channel)).await?;
+                    }
+                }
+                // Nickname already in use
+                ("433", _) => {
+                    nickname.push('_');
+                    self.send_line(&format!("NICK {}", nickname)).await?;
+                }
+                ("PRIVMSG", [target, text]) => {
+                    let Some(source) = message.prefix else {
+                        continue;
+                    };
+                    // Private messages are replied to in private
+                    let reply_to = if target.eq_ignore_ascii_case(&nickname) {
+                        source.split('!').next().unwrap_or(source)
+                    } else {
+                        target
+                    };
+                    self.handle_command(source, target, reply_to, text, server_sender)
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+
+        bail!("connection closed")
+    }
+
+    fn channels(&self) -> impl Iterator<Item = &'static str> {
+        self.config
+            .get()
+            .servers
+            .values()
+            .filter_map(|server| server.irc_channel.as_deref())
+    }
+
+    async fn handle_command(
+        &self,
+        source: &str,
+        target: &str,
+        reply_to: &str,
+        text: &str,
+        server_sender: &UnboundedSender<ServerPacket>,
+    ) -> Result<()> {
+        let Some(text) = text.trim().strip_prefix('!') else {
+            return Ok(());
+        };
+        let (command, cmd) = text.split_once(' ').unwrap_or((text, ""));
+        let cmd = cmd.trim();
+
+        let name = match command {
+            "exec" => {
+                let name = self
+                    .config
+                    .get()
+                    .servers
+                    .iter()
+                    .find(|(_, config)| {
+                        matches!(&config.irc_channel, Some(channel) if channel.eq_ignore_ascii_case(target))
+                    })
+                    .map(|(name, _)| name.clone());
+                match name {
+                    Some(name) => Some(name),
+                    None => {
+                        return self
+                            .notice(reply_to, "Error: not in a linked channel")
+                            .await
+                    }
+                }
+            }
+            "execall" => None,
+            _ => return Ok(()),
+        };
+
+        // Admins come from the live config, so they can be changed without restarting
+        let admins = match &self.config.get().irc {
+            Some(irc_config) => &irc_config.admins[..],
+            None => &[],
+        };
+        let is_admin = admins.iter().any(|mask| wildcard_matches(mask, source));
+        if !is_admin {
+            return self
+                .notice(reply_to, "Error: you aren't allowed to execute commands")
+                .await;
+        }
+        if cmd.is_empty() {
+            return self.notice(reply_to, "Error: expected a command").await;
+        }
+        if let Err(err) = check_command(self.config.get(), name.as_deref(), cmd) {
+            return self
+                .notice(
+                    reply_to,
+                    &format!("Error: {}", render_markdown(&err.to_string())),
+                )
+                .await;
+        }
+
+        debug!("{} executed `{}` from IRC", source, cmd);
+        server_sender
+            .send(ServerPacket {
+                target: Target::from_name(name),
+                event: ServerEvent::ExecCommand {
+                    command: cmd.to_string(),
+                },
+            })
+            .expect("Failed to send server packet");
+
+        self.notice(reply_to, &format!("Executing: {}", cmd)).await
+    }
+
+    async fn notice(&self, target: &str, text: &str) -> Result<()> {
+        self.send_text("NOTICE", target, text).await
+    }
+
+    /// Sends text to a channel or user, one line at a time.
+    async fn send_text(&self, command: &str, target: &str, text: &str) -> Result<()> {
+        for line in text.lines() {
+            // A stray carriage return would end the line early, letting the rest be read as another command
+            let line = line.replace(['\r', '\0'], "");
+            let line = truncate(&line, MAX_MESSAGE_LEN);
+            if !line.is_empty() {
+                self.send_line(&format!("{} {} :{}", command, target, line))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a line, waiting first if lines have been sent too quickly. Other lines wait their turn meanwhile.
+    async fn send_line(&self, line: &str) -> Result<()> {
+        let mut writer = self.write.lock().await;
+        let writer = writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("not connected to IRC"))?;
+        tokio::time::sleep(writer.throttle.wait(Instant::now())).await;
+        writer
+            .write
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for Irc {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        let server_config = self
+            .config
+            .get()
+            .servers
+            .get(server)
+            .ok_or_else(|| anyhow!("unknown server \"{}\"", server))?;
+        let Some(channel) = &server_config.irc_channel else {
+            return Ok(());
+        };
+
+        let text = match message {
+            Message::Chat { text, sent_at } => match sent_at {
+                Some(sent_at) => format!("[{}] {text}", format_time(*sent_at)),
+                None => text.clone(),
+            },
+            Message::Event { description, .. } => description.clone(),
+            Message::Warning { description } => format!("Warning: {description}"),
+        };
+
+        self.send_text("PRIVMSG", channel, &render_markdown(&text))
+            .await
+    }
+}
+
+/// Parses a line like `:nick!user@host PRIVMSG #channel :hello there`.
+fn parse_message(line: &str) -> Option<IrcMessage<'_>> {
+    let mut rest = line.trim_end_matches('\r');
+
+    // Message tags are never requested, but skip them in case they're sent anyway
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+
+    let mut prefix = None;
+    if let Some(stripped) = rest.strip_prefix(':') {
+        let (source, remaining) = stripped.split_once(' ')?;
+        prefix = Some(source);
+        rest = remaining.trim_start();
+    }
+
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    if command.is_empty() {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    while !rest.is_empty() {
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing);
+            break;
+        }
+        let (param, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+        if !param.is_empty() {
+            params.push(param);
+        }
+        rest = remaining;
+    }
+
+    Some(IrcMessage {
+        prefix,
+        command,
+        params,
+    })
+}
+
+/// Converts the bold Markdown used in messages to IRC formatting codes, and removes inline code markers.
+fn render_markdown(text: &str) -> String {
+    text.replace("**", "\x02").replace('`', "")
+}
+
+fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_messages() {
+        let message =
+            parse_message(":nick!user@host PRIVMSG #forge :!exec status; quit\r").unwrap();
+        assert_eq!(message.prefix, Some("nick!user@host"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#forge", "!exec status; quit"]);
+
+        let message = parse_message("PING :irc.example.org").unwrap();
+        assert_eq!(message.prefix, None);
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.params, vec!["irc.example.org"]);
+
+        let message = parse_message(":irc.example.org 001 forge :Welcome").unwrap();
+        assert_eq!(message.command, "001");
+        assert_eq!(message.params, vec!["forge", "Welcome"]);
+    }
+
+    #[test]
+    fn parses_messages_with_tags_and_extra_spaces() {
+        let message = parse_message("@time=now :nick!u@h  JOIN  #forge").unwrap();
+        assert_eq!(message.prefix, Some("nick!u@h"));
+        assert_eq!(message.command, "JOIN");
+        assert_eq!(message.params, vec!["#forge"]);
+    }
+
+    #[test]
+    fn keeps_colons_and_spaces_in_trailing_params() {
+        let message = parse_message("PRIVMSG #forge :a :b  c").unwrap();
+        assert_eq!(message.params, vec!["#forge", "a :b  c"]);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(parse_message("").is_none());
+        assert!(parse_message(":prefix-only").is_none());
+        assert!(parse_message("@tags-only").is_none());
+    }
+
+    #[test]
+    fn renders_markdown_as_irc_formatting() {
+        assert_eq!(
+            render_markdown("**player** (`1`) joined."),
+            "\x02player\x02 (1) joined."
+        );
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 3), "hel");
+        // "é" is two bytes, so cutting after its first byte would split it
+        assert_eq!(truncate("aé", 2), "a");
+        assert_eq!(truncate("aé", 3), "aé");
+    }
+
+    #[test]
+    fn throttle_allows_a_burst_then_spaces_out_lines() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(now);
+        for _ in 0..5 {
+            assert_eq!(throttle.wait(now), Duration::ZERO);
+        }
+        assert_eq!(throttle.wait(now), LINE_INTERVAL);
+        assert_eq!(throttle.wait(now), LINE_INTERVAL * 2);
+
+        // After a while without sending, a full burst is allowed again
+        let later = now + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert_eq!(throttle.wait(later), Duration::ZERO);
+        }
+        assert_eq!(throttle.wait(later), LINE_INTERVAL);
+    }
+}
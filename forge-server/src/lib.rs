@@ -0,0 +1,16 @@
+pub mod api;
+pub mod audit;
+pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod discord;
+pub mod display;
+pub mod irc;
+pub mod matrix;
+pub mod metrics;
+pub mod permissions;
+pub mod recording;
+pub mod schedule;
+pub mod server;
+pub mod sink;
+pub mod state;
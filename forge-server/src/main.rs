@@ -1,18 +1,41 @@
-use crate::config::Config;
-use crate::server::Server;
-use anyhow::Result;
-use forge_shared::{ClientEvent, ClientPacket, ServerEvent, ServerPacket};
-use log::{debug, error, info, warn, LevelFilter};
-use serenity::async_trait;
-use serenity::model::prelude::*;
+use forge_server::api;
+use forge_server::audit::AuditLog;
+use forge_server::config::{Config, LiveConfig};
+#[cfg(unix)]
+use forge_server::control;
+use forge_server::discord::{DiscordSink, Handler};
+use forge_server::display::run_client_display_loop;
+use forge_server::irc::Irc;
+use forge_server::matrix::Matrix;
+use forge_server::metrics::{self, MeteredSink, Metrics};
+use forge_server::permissions::check_command;
+use forge_server::recording::{self, Recorder};
+use forge_server::schedule::Scheduler;
+use forge_server::server::Server;
+use forge_server::sink::{LogSink, Sink, Sinks};
+use forge_server::state::States;
+use forge_shared::{ClientPacket, ServerEvent, ServerPacket, Target};
+use log::{error, info, warn, LevelFilter};
+use serenity::futures::future::try_join_all;
 use serenity::prelude::*;
-use serenity::utils::Color;
-use std::path::Path;
+use std::path::PathBuf;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::{join, try_join};
 
-mod config;
-mod server;
+// How many packets can be waiting for a slow event stream before it starts missing some.
+const PACKET_STREAM_CAPACITY: usize = 1024;
+
+struct Args {
+    config_path: PathBuf,
+    replay: Option<Replay>,
+}
+
+struct Replay {
+    path: PathBuf,
+    speed: f64,
+    live: bool,
+}
 
 #[tokio::main]
 async fn main() {
@@ -26,16 +49,20 @@ async fn main() {
     let mut args = std::env::args();
     let exe_name = args.next().unwrap();
 
-    let Some(config_file_path) = args.next() else {
+    let Some(args) = parse_args(args) else {
         eprintln!("Usage {} [path to config file]", exe_name);
+        eprintln!(
+            "      {} [path to config file] --replay <recording> [--speed <multiplier>] [--live]",
+            exe_name
+        );
         eprintln!();
         std::process::exit(1);
     };
 
     info!("Forge {}", env!("CARGO_PKG_VERSION"));
 
-    let full_config_path = std::env::current_dir().unwrap().join(&config_file_path);
-    let config = match load_config(&full_config_path) {
+    let full_config_path = std::env::current_dir().unwrap().join(&args.config_path);
+    let config = match Config::load(&full_config_path) {
         Ok(config) => config,
         Err(err) => {
             error!("Failed to read config file: {}", err);
@@ -43,273 +70,418 @@ async fn main() {
         }
     };
 
-    let config = Box::leak(Box::new(config));
+    let config = Box::leak(Box::new(LiveConfig::new(full_config_path, config)));
+    let startup_config = config.get();
+
+    if let Some(replay) = args.replay {
+        run_replay(config, &replay).await;
+        return;
+    }
+
+    let recorder = startup_config.record.as_ref().map(|path| {
+        let recorder = Recorder::open(path).unwrap_or_else(|err| {
+            error!("Failed to open recording: {}", err);
+            std::process::exit(1);
+        });
+        info!("Recording packets to {}", path.display());
+        &*Box::leak(Box::new(recorder))
+    });
+
+    let audit_file = startup_config.audit.as_ref().and_then(|audit| audit.file.as_ref());
+    let audit_log = audit_file.map(|path| {
+        let audit_log = AuditLog::open(path).unwrap_or_else(|err| {
+            error!("Failed to open audit log: {}", err);
+            std::process::exit(1);
+        });
+        &*Box::leak(Box::new(audit_log))
+    });
+
+    let server = Server::new(startup_config.listen)
+        .await
+        .expect("Error starting server");
+    info!("Listening on {}", server.local_addr().unwrap());
+    let server = Box::leak(Box::new(server));
+
+    let states = Box::leak(Box::new(States::new()));
+    let metrics = Box::leak(Box::new(Metrics::new(config)));
+    let scheduler = load_scheduler(config);
 
     let (client_sender, client_receiver) = unbounded_channel();
+    let (display_sender, display_receiver) = unbounded_channel();
+    let (packet_sender, _) = broadcast::channel(PACKET_STREAM_CAPACITY);
     let (server_sender, server_receiver) = unbounded_channel();
 
     join!(
-        run_server(config, client_sender, server_receiver),
-        run_client(config, client_receiver, server_sender),
+        run_server(
+            config,
+            server,
+            metrics,
+            recorder,
+            client_sender,
+            server_receiver
+        ),
+        run_packet_loop(
+            states,
+            metrics,
+            recorder,
+            client_receiver,
+            display_sender,
+            packet_sender.clone()
+        ),
+        run_api(
+            config,
+            server,
+            states,
+            server_sender.clone(),
+            packet_sender.clone()
+        ),
+        run_control(config, server, states, server_sender.clone(), packet_sender),
+        run_metrics(config, server, states, metrics),
+        scheduler.run(server_sender.clone()),
+        run_client(
+            config,
+            server,
+            metrics,
+            scheduler,
+            audit_log,
+            display_receiver,
+            server_sender
+        ),
     );
 }
 
-fn load_config(config_path: &Path) -> Result<Config> {
-    Ok(toml::from_str(&std::fs::read_to_string(config_path)?)?)
+fn load_scheduler(config: &'static LiveConfig) -> &'static Scheduler {
+    match Scheduler::load(config) {
+        Ok(scheduler) => Box::leak(Box::new(scheduler)),
+        Err(err) => {
+            error!("Failed to read schedule file: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut replay = None;
+    let mut speed = 1.0;
+    let mut live = false;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replay" => replay = Some(PathBuf::from(args.next()?)),
+            "--speed" => match recording::parse_speed(&args.next()?) {
+                Ok(value) => speed = value,
+                Err(err) => {
+                    eprintln!("Invalid --speed: {}", err);
+                    return None;
+                }
+            },
+            "--live" => live = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let [config_path] = <[String; 1]>::try_from(positional).ok()?;
+    let replay = match replay {
+        Some(path) => Some(Replay {
+            path,
+            speed,
+            live,
+        }),
+        // The replay options don't mean anything without a replay
+        None if speed != 1.0 || live => return None,
+        None => None,
+    };
+
+    Some(Args {
+        config_path: config_path.into(),
+        replay,
+    })
 }
 
 async fn run_server(
-    config: &'static Config,
+    config: &'static LiveConfig,
+    server: &'static Server,
+    metrics: &'static Metrics,
+    recorder: Option<&'static Recorder>,
     client_sender: UnboundedSender<ClientPacket>,
     mut server_receiver: UnboundedReceiver<ServerPacket>,
 ) {
-    let server = Server::new(config.listen)
-        .await
-        .expect("Error starting server");
-    info!("Listening on {}", server.local_addr().unwrap());
-
     let send_loop = async {
         loop {
             let Some(packet) = server_receiver.recv().await else { break };
+            // Commands are checked where they come from too, but this makes sure nothing gets past the filters
+            if let ServerEvent::ExecCommand { command } = &packet.event {
+                let res = match &packet.target {
+                    Target::All => check_command(config.get(), None, command),
+                    Target::Names(names) => names
+                        .iter()
+                        .try_for_each(|name| check_command(config.get(), Some(name), command)),
+                };
+                if let Err(err) = res {
+                    warn!("Not executing command: {}", err);
+                    continue;
+                }
+            }
+            metrics.packet_sent(&packet);
+            if let Some(recorder) = recorder {
+                recorder.sent(&packet);
+            }
             server.send(&packet).await;
         }
     };
 
-    join!(server.receive(client_sender), send_loop,);
+    let heartbeat_config = &config.get().heartbeat;
+    let heartbeat = server.heartbeat(heartbeat_config.interval(), heartbeat_config.timeout());
+
+    join!(server.receive(client_sender), heartbeat, send_loop,);
 }
 
-async fn run_client(
-    config: &'static Config,
-    client_receiver: UnboundedReceiver<ClientPacket>,
-    server_sender: UnboundedSender<ServerPacket>,
+/// Keeps track of each server's state, and passes packets on to be displayed and streamed.
+async fn run_packet_loop(
+    states: &'static States,
+    metrics: &'static Metrics,
+    recorder: Option<&'static Recorder>,
+    mut client_receiver: UnboundedReceiver<ClientPacket>,
+    display_sender: UnboundedSender<ClientPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
 ) {
-    let mut client = Client::builder(&config.discord_token, GatewayIntents::empty())
-        .event_handler(Handler {
-            config,
-            server_sender,
-        })
-        .await
-        .expect("Error creating client");
+    while let Some(packet) = client_receiver.recv().await {
+        metrics.packet_received(&packet);
+        if let Some(recorder) = recorder {
+            recorder.received(&packet);
+        }
+        states.update(&packet).await;
+        // Fails when nothing is subscribed, which is fine
+        let _ = packet_sender.send(packet.clone());
+        display_sender
+            .send(packet)
+            .expect("Failed to send client packet");
+    }
+}
 
-    let http = client.cache_and_http.http.clone();
-    let display_loop = async move {
-        run_client_display_loop(config, http.as_ref(), client_receiver).await;
-        Ok(())
+/// Replays a recording to the log, or to the configured sinks for a live replay, instead of accepting plugin
+/// connections.
+async fn run_replay(config: &'static LiveConfig, replay: &Replay) {
+    let states = Box::leak(Box::new(States::new()));
+    let metrics = Box::leak(Box::new(Metrics::new(config)));
+
+    let (client_sender, client_receiver) = unbounded_channel();
+    let (display_sender, display_receiver) = unbounded_channel();
+    let (packet_sender, _) = broadcast::channel(PACKET_STREAM_CAPACITY);
+
+    let replay_loop = async {
+        if let Err(err) = recording::replay(&replay.path, replay.speed, client_sender).await {
+            error!("Failed to replay recording: {}", err);
+            std::process::exit(1);
+        }
     };
-    let client_start = client.start();
+    let packet_loop = run_packet_loop(
+        states,
+        metrics,
+        None,
+        client_receiver,
+        display_sender,
+        packet_sender,
+    );
 
-    if let Err(err) = try_join!(display_loop, client_start) {
-        error!("Client error: {:?}", err);
+    // Replayed messages only go to Discord, Matrix and IRC when asked for, so they aren't posted by accident
+    if !replay.live {
+        let display_loop = run_client_display_loop(config, &LogSink, display_receiver);
+        join!(replay_loop, packet_loop, display_loop);
+        return;
+    }
+
+    // Commands sent during a replay aren't executed anywhere, and nothing can connect to the server
+    let server = Server::new(([127, 0, 0, 1], 0).into())
+        .await
+        .expect("Error starting server");
+    let server = Box::leak(Box::new(server));
+    // Schedules can be managed, but don't run, and the audit log file is left alone
+    let scheduler = load_scheduler(config);
+    let (server_sender, mut server_receiver) = unbounded_channel::<ServerPacket>();
+    let command_loop = async {
+        while let Some(packet) = server_receiver.recv().await {
+            info!("Not sending during replay: {}", packet.event);
+        }
+    };
+
+    join!(
+        replay_loop,
+        packet_loop,
+        command_loop,
+        run_client(
+            config,
+            server,
+            metrics,
+            scheduler,
+            None,
+            display_receiver,
+            server_sender
+        ),
+    );
+}
+
+async fn run_api(
+    config: &'static LiveConfig,
+    server: &'static Server,
+    states: &'static States,
+    server_sender: UnboundedSender<ServerPacket>,
+    packet_sender: broadcast::Sender<ClientPacket>,
+) {
+    let Some(api_config) = &config.get().api else { return };
+
+    let res = api::serve(
+        config,
+        api_config,
+        server,
+        states,
+        server_sender,
+        packet_sender,
+    );
+    if let Err(err) = res.await {
+        error!("API error: {}", err);
         std::process::exit(1);
     }
 }
 
-async fn run_client_display_loop(
-    config: &'static Config,
-    http: &serenity::http::Http,
-    mut client_receiver: UnboundedReceiver<ClientPacket>,
+async fn run_metrics(
+    config: &'static LiveConfig,
+    server: &'static Server,
+    states: &'static States,
+    metrics: &'static Metrics,
 ) {
-    loop {
-        let packet = client_receiver
-            .recv()
-            .await
-            .expect("Failed to receive packet");
-        let Some(server_config) = config.servers.get(&packet.name) else {
-            warn!("Event from unknown client \"{}\": {}", packet.name, packet.event);
-            continue;
-        };
-        let channel = ChannelId(server_config.channel);
-
-        let res = match packet.event {
-            ClientEvent::GameStart { map, mode } => {
-                let map_en = config
-                    .maps
-                    .get(&map)
-                    .cloned()
-                    .unwrap_or_else(|| format!("`{}`", map));
-                let mode_en = config
-                    .modes
-                    .get(&mode)
-                    .cloned()
-                    .unwrap_or_else(|| format!("`{}`", mode));
-
-                channel
-                    .send_message(http, |m| {
-                        m.embed(|embed| {
-                            embed.description(format!("Starting **{mode_en}** on **{map_en}**."))
-                        })
-                    })
-                    .await
-                    .map(|_| ())
-            }
-            ClientEvent::ClientConnecting { name, uid } => channel
-                .send_message(http, |m| {
-                    m.embed(|embed| embed.description(format!("**{name}** (`{uid}`) joined.")))
-                })
-                .await
-                .map(|_| ()),
-            ClientEvent::ClientDisconnected { name, uid } => channel
-                .send_message(http, |m| {
-                    m.embed(|embed| embed.description(format!("**{name}** (`{uid}`) left.")))
-                })
-                .await
-                .map(|_| ()),
-            ClientEvent::ClientChat {
-                name,
-                message,
-                is_team,
-                ..
-            } => channel
-                .send_message(http, |m| {
-                    m.content(format!(
-                        "{}**{name}**: {message}",
-                        if is_team { "[TEAM] " } else { "" }
-                    ))
-                })
-                .await
-                .map(|_| ()),
-        };
+    let Some(metrics_config) = &config.get().metrics else { return };
 
-        if let Err(err) = res {
-            error!("Failed to send Discord message: {}", err);
-        }
+    if let Err(err) = metrics::serve(metrics_config.listen, metrics, server, states).await {
+        error!("Metrics error: {}", err);
+        std::process::exit(1);
     }
 }
 
-struct Handler {
-    config: &'static Config,
+#[cfg(unix)]
+async fn run_control(
+    config: &'static LiveConfig,
+    server: &'static Server,
+    states: &'static States,
     server_sender: UnboundedSender<ServerPacket>,
-}
-
-#[async_trait]
-impl EventHandler for Handler {
-    async fn ready(&self, ctx: Context, ready: Ready) {
-        info!("Connected to Discord as {}", ready.user.name);
-
-        // Register commands
-        debug!("Registering commands...");
-        command::Command::set_global_application_commands(&ctx.http, |commands| {
-            commands
-                .create_application_command(|command| {
-                    command
-                        .name("exec")
-                        .description("Execute a command on a server.")
-                        .create_option(|option| {
-                            option
-                                .name("command")
-                                .description("A command to execute.")
-                                .kind(command::CommandOptionType::String)
-                                .required(true)
-                        })
-                })
-                .create_application_command(|command| {
-                    command
-                        .name("execall")
-                        .description("Execute a command on all servers.")
-                        .create_option(|option| {
-                            option
-                                .name("command")
-                                .description("A command to execute.")
-                                .kind(command::CommandOptionType::String)
-                                .required(true)
-                        })
-                })
-        })
-        .await
-        .unwrap();
+    packet_sender: broadcast::Sender<ClientPacket>,
+) {
+    let Some(path) = &config.get().control_socket else { return };
 
-        debug!("😎");
+    let res = control::serve(path, config, server, states, server_sender, packet_sender);
+    if let Err(err) = res.await {
+        error!("Control socket error: {}", err);
+        std::process::exit(1);
     }
+}
 
-    async fn interaction_create(&self, ctx: Context, interaction: interaction::Interaction) {
-        let command = match interaction {
-            interaction::Interaction::ApplicationCommand(command) => command,
-            _ => return,
-        };
+#[cfg(not(unix))]
+async fn run_control(
+    config: &'static LiveConfig,
+    _server: &'static Server,
+    _states: &'static States,
+    _server_sender: UnboundedSender<ServerPacket>,
+    _packet_sender: broadcast::Sender<ClientPacket>,
+) {
+    if config.get().control_socket.is_some() {
+        warn!("The control socket is only supported on Unix");
+    }
+}
 
-        match command.data.name.as_str() {
-            "exec" => {
-                let cmd = match command.data.options[0].resolved.as_ref() {
-                    Some(interaction::application_command::CommandDataOptionValue::String(val)) => {
-                        val
-                    }
-                    _ => unreachable!(),
-                };
+async fn run_client(
+    config: &'static LiveConfig,
+    server: &'static Server,
+    metrics: &'static Metrics,
+    scheduler: &'static Scheduler,
+    audit_log: Option<&'static AuditLog>,
+    client_receiver: UnboundedReceiver<ClientPacket>,
+    server_sender: UnboundedSender<ServerPacket>,
+) {
+    let startup_config = config.get();
+    let matrix = startup_config.matrix.as_ref().map(|matrix_config| {
+        Matrix::new(config, matrix_config).unwrap_or_else(|err| {
+            error!("Failed to set up Matrix: {}", err);
+            std::process::exit(1);
+        })
+    });
+    let irc = startup_config
+        .irc
+        .as_ref()
+        .map(|irc_config| Irc::new(config, irc_config));
+
+    // The main bot, then every other bot
+    let main_bot = (
+        None,
+        &startup_config.discord_token,
+        startup_config.discord_application,
+    );
+    let other_bots = startup_config
+        .bots
+        .iter()
+        .map(|bot| (Some(bot.name.as_str()), &bot.token, bot.application));
+    let mut clients = Vec::new();
+    let mut discord_sinks = Vec::new();
+    for (bot, token, application) in std::iter::once(main_bot).chain(other_bots) {
+        let client = Client::builder(token, GatewayIntents::empty())
+            .application_id(application)
+            .event_handler(Handler::new(
+                config,
+                bot,
+                server,
+                scheduler,
+                audit_log,
+                server_sender.clone(),
+            ))
+            .await
+            .expect("Error creating client");
 
-                let client_name = self
-                    .config
-                    .servers
-                    .iter()
-                    .find(|(_, config)| config.channel == command.channel_id.0)
-                    .map(|(name, _)| name);
-                match client_name {
-                    Some(name) => {
-                        self.server_sender
-                            .send(ServerPacket {
-                                name: Some(name.to_string()),
-                                event: ServerEvent::ExecCommand {
-                                    command: cmd.clone(),
-                                },
-                            })
-                            .expect("Failed to send server packet");
-
-                        command
-                            .create_interaction_response(&ctx.http, |r| interaction_command(r, cmd))
-                            .await
-                            .unwrap();
-                    }
-                    None => {
-                        command
-                            .create_interaction_response(&ctx.http, |r| {
-                                interaction_error(r, "not in a linked channel")
-                            })
-                            .await
-                            .unwrap();
-                    }
-                }
-            }
-            "execall" => {
-                let cmd = match command.data.options[0].resolved.as_ref() {
-                    Some(interaction::application_command::CommandDataOptionValue::String(val)) => {
-                        val
-                    }
-                    _ => unreachable!(),
-                };
+        let name: &'static str = match bot {
+            Some(bot) => Box::leak(format!("discord-{}", bot).into_boxed_str()),
+            None => "discord",
+        };
+        let http = client.cache_and_http.http.clone();
+        discord_sinks.push(DiscordSink::new(config, bot, name, metrics, http));
+        clients.push(client);
+    }
 
-                self.server_sender
-                    .send(ServerPacket {
-                        name: None,
-                        event: ServerEvent::ExecCommand {
-                            command: cmd.clone(),
-                        },
-                    })
-                    .expect("Failed to send server packet");
-
-                command
-                    .create_interaction_response(&ctx.http, |r| interaction_command(r, cmd))
-                    .await
-                    .unwrap();
-            }
-            _ => {}
-        }
+    // Discord sinks record their own metrics, since they post in the background
+    let mut metered_sinks = Vec::new();
+    if let Some(matrix) = &matrix {
+        metered_sinks.push(MeteredSink::new("matrix", matrix, metrics));
     }
-}
+    if let Some(irc) = &irc {
+        metered_sinks.push(MeteredSink::new("irc", irc, metrics));
+    }
+    let discord_sinks = discord_sinks.iter().map(|sink| sink as &dyn Sink);
+    let sinks = Sinks(
+        discord_sinks
+            .chain(metered_sinks.iter().map(|sink| sink as &dyn Sink))
+            .collect(),
+    );
 
-fn interaction_error<'a, 'b>(
-    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
-    err: &str,
-) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
-    let err_str = format!("Error: {}", err);
-    response.interaction_response_data(|data| {
-        data.ephemeral(true)
-            .embed(|embed| embed.color(Color::new(0xFF0000)).description(err_str))
-    })
-}
+    let display_loop = async {
+        run_client_display_loop(config, &sinks, client_receiver).await;
+        Ok(())
+    };
+    let matrix_loop = async {
+        match &matrix {
+            Some(matrix) => matrix.run(server_sender.clone()).await,
+            None => std::future::pending().await,
+        }
+        Ok(())
+    };
+    let irc_loop = async {
+        match &irc {
+            Some(irc) => irc.run(server_sender.clone()).await,
+            None => std::future::pending().await,
+        }
+        Ok(())
+    };
+    let clients_start = try_join_all(clients.iter_mut().map(|client| client.start()));
 
-fn interaction_command<'a, 'b>(
-    response: &'a mut serenity::builder::CreateInteractionResponse<'b>,
-    cmd: &str,
-) -> &'a mut serenity::builder::CreateInteractionResponse<'b> {
-    let str = format!("```{}```", cmd);
-    response.interaction_response_data(|data| data.ephemeral(true).content(str))
+    if let Err(err) = try_join!(display_loop, matrix_loop, irc_loop, clients_start) {
+        error!("Client error: {:?}", err);
+        std::process::exit(1);
+    }
 }
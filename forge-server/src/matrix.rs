@@ -0,0 +1,466 @@
+use crate::config::{LiveConfig, MatrixConfig};
+use crate::permissions::check_command;
+use crate::sink::{format_time, Message, Sink};
+use anyhow::{anyhow, bail, Result};
+use forge_shared::{ServerEvent, ServerPacket, Target};
+use log::{debug, error, info, warn};
+use reqwest::{Method, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+
+// How long the homeserver can hold a sync request open waiting for new events.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+// How long to wait before trying again after a request to the homeserver fails.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A Matrix client that displays messages in the room linked to each server, and executes commands sent in those
+/// rooms by admins.
+pub struct Matrix {
+    config: &'static LiveConfig,
+    matrix_config: &'static MatrixConfig,
+    http: reqwest::Client,
+    homeserver: Url,
+    // Transaction IDs have to be unique for the access token, even across restarts
+    transaction_prefix: u64,
+    next_transaction: AtomicU64,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: SyncRooms,
+}
+
+#[derive(Deserialize, Default)]
+struct SyncRooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoom {
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+#[derive(Deserialize, Default)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: Value,
+}
+
+impl Matrix {
+    pub fn new(config: &'static LiveConfig, matrix_config: &'static MatrixConfig) -> Result<Self> {
+        let homeserver = Url::parse(&matrix_config.homeserver)?;
+        if homeserver.cannot_be_a_base() {
+            bail!("invalid homeserver URL \"{}\"", matrix_config.homeserver);
+        }
+
+        let transaction_prefix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Ok(Matrix {
+            config,
+            matrix_config,
+            http: reqwest::Client::new(),
+            homeserver,
+            transaction_prefix,
+            next_transaction: AtomicU64::new(0),
+        })
+    }
+
+    /// Syncs with the homeserver forever, executing commands as they're received.
+    pub async fn run(&self, server_sender: UnboundedSender<ServerPacket>) {
+        let user_id = loop {
+            match self.request::<WhoAmI>(Method::GET, &["account", "whoami"], &[], None).await {
+                Ok(whoami) => break whoami.user_id,
+                Err(err) => {
+                    error!("Failed to log in to Matrix: {}", err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        };
+        info!("Connected to Matrix as {}", user_id);
+
+        for room in self.rooms() {
+            let res = self
+                .request::<Value>(Method::POST, &["join", room], &[], Some(json!({})))
+                .await;
+            if let Err(err) = res {
+                warn!("Failed to join Matrix room {}: {}", room, err);
+            }
+        }
+
+        // The first sync only finds where the timeline ends, so old commands aren't executed again
+        let mut since = None;
+        loop {
+            let response = match self.sync(since.as_deref()).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error!("Matrix sync failed: {}", err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            if since.is_some() {
+                for (room, joined) in response.rooms.join {
+                    for event in joined.timeline.events {
+                        if event.kind != "m.room.message" || event.sender == user_id {
+                            continue;
+                        }
+                        let Some(body) = event.content["body"].as_str() else { continue };
+                        self.handle_command(&room, &event.sender, body, &server_sender)
+                            .await;
+                    }
+                }
+            }
+            since = Some(response.next_batch);
+        }
+    }
+
+    fn rooms(&self) -> impl Iterator<Item = &'static str> {
+        self.config
+            .get()
+            .servers
+            .values()
+            .filter_map(|server| server.matrix_room.as_deref())
+    }
+
+    async fn sync(&self, since: Option<&str>) -> Result<SyncResponse> {
+        let filter = match since {
+            Some(_) => json!({ "room": { "timeline": { "types": ["m.room.message"] } } }),
+            None => json!({ "room": { "timeline": { "limit": 1 } } }),
+        };
+        let filter = filter.to_string();
+        let timeout = match since {
+            Some(_) => SYNC_TIMEOUT.as_millis().to_string(),
+            None => "0".to_string(),
+        };
+
+        let mut query = vec![("filter", filter.as_str()), ("timeout", timeout.as_str())];
+        if let Some(since) = since {
+            query.push(("since", since));
+        }
+
+        self.request(Method::GET, &["sync"], &query, None).await
+    }
+
+    async fn handle_command(
+        &self,
+        room: &str,
+        sender: &str,
+        body: &str,
+        server_sender: &UnboundedSender<ServerPacket>,
+    ) {
+        let Some(command) = parse_command(body) else { return };
+
+        let (name, cmd) = match command {
+            RoomCommand::Exec(cmd) => {
+                let name = self
+                    .config
+                    .get()
+                    .servers
+                    .iter()
+                    .find(|(_, config)| config.matrix_room.as_deref() == Some(room))
+                    .map(|(name, _)| name.clone());
+                match name {
+                    Some(name) => (Some(name), cmd),
+                    None => {
+                        self.reply_error(room, "not in a linked room").await;
+                        return;
+                    }
+                }
+            }
+            RoomCommand::ExecAll(cmd) => (None, cmd),
+        };
+
+        // Admins come from the live config, so they can be changed without restarting
+        let admins = match &self.config.get().matrix {
+            Some(matrix_config) => &matrix_config.admins[..],
+            None => &[],
+        };
+        if !admins.iter().any(|admin| admin == sender) {
+            self.reply_error(room, "you aren't allowed to execute commands").await;
+            return;
+        }
+        if cmd.is_empty() {
+            self.reply_error(room, "expected a command").await;
+            return;
+        }
+        if let Err(err) = check_command(self.config.get(), name.as_deref(), cmd) {
+            self.reply_error(room, &err.to_string()).await;
+            return;
+        }
+
+        debug!("{} executed `{}` from Matrix", sender, cmd);
+        server_sender
+            .send(ServerPacket {
+                target: Target::from_name(name),
+                event: ServerEvent::ExecCommand {
+                    command: cmd.to_string(),
+                },
+            })
+            .expect("Failed to send server packet");
+
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": cmd,
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!("<pre><code>{}</code></pre>", escape_html(cmd)),
+        });
+        if let Err(err) = self.send_message(room, content).await {
+            error!("Failed to send Matrix message: {}", err);
+        }
+    }
+
+    async fn reply_error(&self, room: &str, err: &str) {
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": format!("Error: {}", err),
+        });
+        if let Err(err) = self.send_message(room, content).await {
+            error!("Failed to send Matrix message: {}", err);
+        }
+    }
+
+    async fn send_message(&self, room: &str, content: Value) -> Result<()> {
+        let transaction = format!(
+            "forge-{}-{}",
+            self.transaction_prefix,
+            self.next_transaction.fetch_add(1, Ordering::Relaxed)
+        );
+        self.request::<Value>(
+            Method::PUT,
+            &["rooms", room, "send", "m.room.message", &transaction],
+            &[],
+            Some(content),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &[&str],
+        query: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> Result<T> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(path);
+
+        let mut request = self
+            .http
+            .request(method, url)
+            .bearer_auth(&self.matrix_config.access_token)
+            .query(query);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            let err = body["error"].as_str().unwrap_or("unknown error");
+            return Err(anyhow!("{} ({})", err, status));
+        }
+
+        Ok(serde_json::from_value(body)?)
+    }
+}
+
+#[async_trait]
+impl Sink for Matrix {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        let server_config = self
+            .config
+            .get()
+            .servers
+            .get(server)
+            .ok_or_else(|| anyhow!("unknown server \"{}\"", server))?;
+        let Some(room) = &server_config.matrix_room else { return Ok(()) };
+
+        let (msgtype, text) = match message {
+            Message::Chat { text, sent_at } => match sent_at {
+                Some(sent_at) => ("m.text", format!("[{}] {text}", format_time(*sent_at))),
+                None => ("m.text", text.clone()),
+            },
+            Message::Event { description, .. } => ("m.notice", description.clone()),
+            Message::Warning { description } => ("m.notice", format!("⚠️ {description}")),
+        };
+
+        let (body, formatted_body) = render_markdown(&text);
+        let content = json!({
+            "msgtype": msgtype,
+            "body": body,
+            "format": "org.matrix.custom.html",
+            "formatted_body": formatted_body,
+        });
+        self.send_message(room, content).await
+    }
+}
+
+/// A command sent in a room.
+#[derive(Debug, PartialEq, Eq)]
+enum RoomCommand<'a> {
+    /// `!exec <command>`, executed on the room's server.
+    Exec(&'a str),
+    /// `!execall <command>`, executed on every server.
+    ExecAll(&'a str),
+}
+
+/// Parses a message as a command, or returns `None` if it isn't one. The command to execute can be empty.
+fn parse_command(body: &str) -> Option<RoomCommand<'_>> {
+    let body = body.trim().strip_prefix('!')?;
+    let (command, cmd) = body.split_once(' ').unwrap_or((body, ""));
+    let cmd = cmd.trim();
+
+    match command {
+        "exec" => Some(RoomCommand::Exec(cmd)),
+        "execall" => Some(RoomCommand::ExecAll(cmd)),
+        _ => None,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Converts the bold and inline code Markdown used in messages to plain text and HTML.
+fn render_markdown(text: &str) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::new();
+    let mut bold = false;
+    let mut code = false;
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        if !code && rest.starts_with("**") {
+            html.push_str(if bold { "</b>" } else { "<b>" });
+            bold = !bold;
+            rest = &rest[2..];
+        } else if rest.starts_with('`') {
+            html.push_str(if code { "</code>" } else { "<code>" });
+            code = !code;
+            rest = &rest[1..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            plain.push(c);
+            html.push_str(&escape_html(c.encode_utf8(&mut [0; 4])));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    if code {
+        html.push_str("</code>");
+    }
+    if bold {
+        html.push_str("</b>");
+    }
+
+    (plain, html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse_command("!exec status"),
+            Some(RoomCommand::Exec("status"))
+        );
+        assert_eq!(
+            parse_command("  !execall kick player  "),
+            Some(RoomCommand::ExecAll("kick player"))
+        );
+        assert_eq!(
+            parse_command("!exec   status; quit"),
+            Some(RoomCommand::Exec("status; quit"))
+        );
+        assert_eq!(parse_command("!exec"), Some(RoomCommand::Exec("")));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert_eq!(parse_command("exec status"), None);
+        assert_eq!(parse_command("!execute status"), None);
+        assert_eq!(parse_command("!help"), None);
+        assert_eq!(parse_command("hello"), None);
+        assert_eq!(parse_command(""), None);
+    }
+
+    #[test]
+    fn renders_bold_and_code() {
+        assert_eq!(
+            render_markdown("**player** (`1`) joined."),
+            (
+                "player (1) joined.".to_string(),
+                "<b>player</b> (<code>1</code>) joined.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            render_markdown("<b>&\"</b>"),
+            (
+                "<b>&\"</b>".to_string(),
+                "&lt;b&gt;&amp;&quot;&lt;/b&gt;".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_asterisks_in_code() {
+        assert_eq!(
+            render_markdown("`kick **`"),
+            ("kick **".to_string(), "<code>kick **</code>".to_string())
+        );
+    }
+
+    #[test]
+    fn closes_unclosed_tags() {
+        assert_eq!(
+            render_markdown("**bold `code"),
+            (
+                "bold code".to_string(),
+                "<b>bold <code>code</code></b>".to_string()
+            )
+        );
+    }
+}
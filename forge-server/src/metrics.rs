@@ -0,0 +1,293 @@
+use crate::config::LiveConfig;
+use crate::server::Server;
+use crate::sink::{Message, Sink};
+use crate::state::States;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use forge_shared::{ClientPacket, ServerEvent, ServerPacket, Target};
+use log::info;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use serenity::async_trait;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Label for servers that aren't configured, so plugins can't create a new time series for every name they send
+const UNKNOWN_SERVER: &str = "unknown";
+
+/// Prometheus metrics for everything passing through the bot.
+pub struct Metrics {
+    config: &'static LiveConfig,
+    registry: Registry,
+    // Held while the gauges are rebuilt and gathered, so concurrent scrapes don't see them half reset
+    encoding: Mutex<()>,
+    packets_received: IntCounterVec,
+    packets_sent: IntCounterVec,
+    commands_executed: IntCounterVec,
+    sink_failures: IntCounterVec,
+    sink_send_seconds: HistogramVec,
+    decode_errors: IntCounter,
+    connected_plugins: IntGaugeVec,
+    plugin_latency_seconds: GaugeVec,
+    players_online: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new(config: &'static LiveConfig) -> Self {
+        let registry = Registry::new();
+
+        let packets_received = IntCounterVec::new(
+            Opts::new(
+                "forge_packets_received_total",
+                "Packets received from plugins.",
+            ),
+            &["server", "event"],
+        )
+        .unwrap();
+        let packets_sent = IntCounterVec::new(
+            Opts::new("forge_packets_sent_total", "Packets sent to plugins."),
+            &["event"],
+        )
+        .unwrap();
+        let commands_executed = IntCounterVec::new(
+            Opts::new(
+                "forge_commands_executed_total",
+                "Commands sent to be executed, by target server, or `all` for every server.",
+            ),
+            &["server"],
+        )
+        .unwrap();
+        let sink_failures = IntCounterVec::new(
+            Opts::new(
+                "forge_sink_failures_total",
+                "Messages that failed to be displayed.",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let sink_send_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "forge_sink_send_seconds",
+                "Time taken to display a message, including failures.",
+            ),
+            &["sink"],
+        )
+        .unwrap();
+        let decode_errors = IntCounter::new(
+            "forge_decode_errors_total",
+            "Plugin connections dropped for sending something that couldn't be decoded.",
+        )
+        .unwrap();
+        let connected_plugins = IntGaugeVec::new(
+            Opts::new(
+                "forge_connected_plugins",
+                "Plugins connected with each name.",
+            ),
+            &["server"],
+        )
+        .unwrap();
+        let plugin_latency_seconds = GaugeVec::new(
+            Opts::new(
+                "forge_plugin_latency_seconds",
+                "Round-trip time of the last heartbeat to each plugin.",
+            ),
+            &["server"],
+        )
+        .unwrap();
+        let players_online = IntGaugeVec::new(
+            Opts::new("forge_players_online", "Players on each server."),
+            &["server"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(packets_received.clone()))
+            .unwrap();
+        registry.register(Box::new(packets_sent.clone())).unwrap();
+        registry
+            .register(Box::new(commands_executed.clone()))
+            .unwrap();
+        registry.register(Box::new(sink_failures.clone())).unwrap();
+        registry
+            .register(Box::new(sink_send_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(decode_errors.clone())).unwrap();
+        registry
+            .register(Box::new(connected_plugins.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(plugin_latency_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(players_online.clone())).unwrap();
+
+        Metrics {
+            config,
+            registry,
+            encoding: Mutex::new(()),
+            packets_received,
+            packets_sent,
+            commands_executed,
+            sink_failures,
+            sink_send_seconds,
+            decode_errors,
+            connected_plugins,
+            plugin_latency_seconds,
+            players_online,
+        }
+    }
+
+    pub fn packet_received(&self, packet: &ClientPacket) {
+        self.packets_received
+            .with_label_values(&[self.server_label(&packet.name), packet.event.kind()])
+            .inc();
+    }
+
+    pub fn packet_sent(&self, packet: &ServerPacket) {
+        self.packets_sent
+            .with_label_values(&[packet.event.kind()])
+            .inc();
+        if let ServerEvent::ExecCommand { .. } = packet.event {
+            match &packet.target {
+                Target::All => self.commands_executed.with_label_values(&["all"]).inc(),
+                Target::Names(names) => {
+                    for name in names {
+                        self.commands_executed
+                            .with_label_values(&[self.server_label(name)])
+                            .inc();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records how long a sink took to display a message, and whether it failed.
+    pub fn observe_send(&self, sink: &str, duration: Duration, failed: bool) {
+        self.sink_send_seconds
+            .with_label_values(&[sink])
+            .observe(duration.as_secs_f64());
+        if failed {
+            self.sink_failures.with_label_values(&[sink]).inc();
+        }
+    }
+
+    /// The label for a server name, which is [`UNKNOWN_SERVER`] unless the server is configured.
+    fn server_label<'a>(&self, name: &'a str) -> &'a str {
+        if self.config.get().servers.contains_key(name) {
+            name
+        } else {
+            UNKNOWN_SERVER
+        }
+    }
+
+    /// Updates the metrics that are read from the current state, and encodes every metric.
+    async fn encode(&self, server: &Server, states: &States) -> String {
+        let _encoding = self.encoding.lock().await;
+
+        self.connected_plugins.reset();
+        self.plugin_latency_seconds.reset();
+        for connection in server.connections().await {
+            let Some(name) = &connection.name else {
+                continue;
+            };
+            let label = self.server_label(name);
+            self.connected_plugins.with_label_values(&[label]).inc();
+            // Latencies of unknown servers can't be told apart, so they're left out
+            if let Some(latency) = connection.latency.filter(|_| label != UNKNOWN_SERVER) {
+                self.plugin_latency_seconds
+                    .with_label_values(&[label])
+                    .set(latency.as_secs_f64());
+            }
+        }
+
+        self.players_online.reset();
+        for (name, count) in states.player_counts().await {
+            self.players_online
+                .with_label_values(&[self.server_label(&name)])
+                .add(count as i64);
+        }
+
+        let decode_errors = server.decode_errors();
+        self.decode_errors
+            .inc_by(decode_errors.saturating_sub(self.decode_errors.get()));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+        String::from_utf8(buffer).expect("Metrics aren't UTF-8")
+    }
+}
+
+/// A sink that records how long another sink takes to display each message, and how often it fails.
+pub struct MeteredSink<'a> {
+    name: &'static str,
+    sink: &'a dyn Sink,
+    metrics: &'a Metrics,
+}
+
+impl<'a> MeteredSink<'a> {
+    pub fn new(name: &'static str, sink: &'a dyn Sink, metrics: &'a Metrics) -> Self {
+        MeteredSink {
+            name,
+            sink,
+            metrics,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MeteredSink<'_> {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        let start = Instant::now();
+        let res = self.sink.send(server, message).await;
+        self.metrics
+            .observe_send(self.name, start.elapsed(), res.is_err());
+        res
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MetricsState {
+    metrics: &'static Metrics,
+    server: &'static Server,
+    states: &'static States,
+}
+
+/// Serves metrics at `/metrics` until it fails.
+pub async fn serve(
+    listen: SocketAddr,
+    metrics: &'static Metrics,
+    server: &'static Server,
+    states: &'static States,
+) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(get_metrics))
+        .with_state(MetricsState {
+            metrics,
+            server,
+            states,
+        });
+
+    let http_server = axum::Server::try_bind(&listen)?;
+    info!("Metrics listening on {}", listen);
+    http_server.serve(app.into_make_service()).await?;
+    Ok(())
+}
+
+async fn get_metrics(State(state): State<MetricsState>) -> impl IntoResponse {
+    let body = state.metrics.encode(state.server, state.states).await;
+    (
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        body,
+    )
+}
@@ -0,0 +1,309 @@
+use crate::config::Config;
+use forge_shared::filter::{split_commands, CommandDenied};
+
+// What a permission's `commands` has to match to announce in chat. It isn't a real command, so command filters don't
+// apply to it.
+pub const ANNOUNCE: &str = "announce";
+// What a permission's `commands` has to match to see the audit log's actions on its servers, which isn't a real command
+// either.
+pub const AUDIT: &str = "audit";
+
+/// A Discord user, along with the roles they have in the server the command came from.
+pub struct DiscordUser<'a> {
+    pub id: u64,
+    pub roles: &'a [u64],
+}
+
+/// Checks whether a user can execute a command on a server. Each command in the line is checked on its own, split like
+/// [`split_commands`] does, since the game executes them all.
+pub fn can_execute(config: &Config, user: &DiscordUser, server: &str, command: &str) -> bool {
+    if config.permissions.is_empty() {
+        return true;
+    }
+
+    let allows = |command: &str| {
+        config.permissions.iter().any(|permission| {
+            let applies = permission.users.contains(&user.id)
+                || permission
+                    .roles
+                    .iter()
+                    .any(|role| user.roles.contains(role));
+            applies
+                && permission
+                    .servers
+                    .iter()
+                    .any(|pattern| wildcard_matches(pattern, server))
+                && permission
+                    .commands
+                    .iter()
+                    .any(|pattern| wildcard_matches(pattern, command))
+        })
+    };
+
+    let parts = split_commands(command);
+    if parts.is_empty() {
+        return allows("");
+    }
+    parts.into_iter().all(allows)
+}
+
+/// Checks a command against the global command filter and the filter of a server, or every configured server if
+/// `server` is `None`.
+pub fn check_command(
+    config: &Config,
+    server: Option<&str>,
+    command: &str,
+) -> Result<(), CommandDenied> {
+    config.commands.check(command)?;
+    match server {
+        Some(server) => {
+            if let Some(server_config) = config.servers.get(server) {
+                server_config.commands.check(command)?;
+            }
+        }
+        None => {
+            for server_config in config.servers.values() {
+                server_config.commands.check(command)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether a user can execute a command on a server, or every configured server if `server` is `None`, and
+/// explains why not if they can't. Commands the command filters deny can't be executed by anyone.
+pub fn check_execute(
+    config: &Config,
+    user: &DiscordUser,
+    server: Option<&str>,
+    command: &str,
+) -> Result<(), String> {
+    check_command(config, server, command).map_err(|err| err.to_string())?;
+
+    match denied_server(config, user, server, command) {
+        Some(server) => Err(format!(
+            "you aren't allowed to execute `{}` on **{}**",
+            command.trim(),
+            server
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether a user can announce in chat on a server, or every configured server if `server` is `None`, which
+/// needs a permission allowing the [`ANNOUNCE`] command.
+pub fn check_announce(
+    config: &Config,
+    user: &DiscordUser,
+    server: Option<&str>,
+) -> Result<(), String> {
+    match denied_server(config, user, server, ANNOUNCE) {
+        Some(server) => Err(format!("you aren't allowed to announce on **{}**", server)),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether a user can see the audit log's actions on a server, or every configured server if `server` is `None`,
+/// which needs a permission allowing the [`AUDIT`] command.
+pub fn check_audit(
+    config: &Config,
+    user: &DiscordUser,
+    server: Option<&str>,
+) -> Result<(), String> {
+    match denied_server(config, user, server, AUDIT) {
+        Some(server) => Err(format!(
+            "you aren't allowed to see the audit log of **{}**",
+            server
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The first server the user can't execute a command on, out of one server or every configured server.
+fn denied_server<'a>(
+    config: &'a Config,
+    user: &DiscordUser,
+    server: Option<&'a str>,
+    command: &str,
+) -> Option<&'a str> {
+    match server {
+        Some(server) => (!can_execute(config, user, server, command)).then_some(server),
+        None => {
+            let mut names: Vec<&str> = config.servers.keys().map(|name| name as &str).collect();
+            names.sort_unstable();
+            names
+                .into_iter()
+                .find(|server| !can_execute(config, user, server, command))
+        }
+    }
+}
+
+/// Checks whether commands from a Discord guild, or from outside one if `guild` is `None`, can control a server. Any
+/// guild can control every server if no guilds are configured.
+pub fn guild_controls(config: &Config, guild: Option<u64>, server: &str) -> bool {
+    if config.guilds.is_empty() {
+        return true;
+    }
+
+    config.guilds.iter().any(|guild_config| {
+        Some(guild_config.id) == guild
+            && guild_config
+                .servers
+                .iter()
+                .any(|pattern| wildcard_matches(pattern, server))
+    })
+}
+
+/// Checks whether any command in a line, split like [`split_commands`] does, has to be confirmed before it's executed
+/// from Discord.
+pub fn needs_confirmation(config: &Config, command: &str) -> bool {
+    split_commands(command).into_iter().any(|part| {
+        config
+            .confirm
+            .commands
+            .iter()
+            .any(|pattern| wildcard_matches(pattern, part))
+    })
+}
+
+/// Checks whether text matches a pattern, ignoring case. `*` in the pattern matches any number of characters, and `?`
+/// matches one.
+pub fn wildcard_matches(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[u8], text: &[u8]) -> bool {
+        match (pattern.first(), text.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => {
+                matches(&pattern[1..], text) || (!text.is_empty() && matches(pattern, &text[1..]))
+            }
+            (Some(b'?'), Some(_)) => matches(&pattern[1..], &text[1..]),
+            (Some(p), Some(t)) if p == t => matches(&pattern[1..], &text[1..]),
+            _ => false,
+        }
+    }
+
+    matches(
+        pattern.to_ascii_lowercase().as_bytes(),
+        text.to_ascii_lowercase().as_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            listen = "127.0.0.1:0"
+            discord-token = ""
+            discord-application = 0
+
+            [[permissions]]
+            roles = [1]
+            servers = ["eu-*"]
+            commands = ["kick *", "status"]
+
+            [[permissions]]
+            users = [2]
+            commands = ["*"]
+
+            [servers.eu-1]
+            [servers.us-1]
+
+            [maps]
+            [modes]
+            "#,
+        )
+        .unwrap()
+    }
+
+    const MODERATOR: DiscordUser = DiscordUser {
+        id: 10,
+        roles: &[1],
+    };
+    const ADMIN: DiscordUser = DiscordUser { id: 2, roles: &[] };
+    const NOBODY: DiscordUser = DiscordUser { id: 3, roles: &[] };
+
+    #[test]
+    fn wildcards_match_any_characters() {
+        assert!(wildcard_matches("kick *", "kick player"));
+        assert!(wildcard_matches("kick *", "kick "));
+        assert!(!wildcard_matches("kick *", "kick"));
+        assert!(wildcard_matches("eu-?", "eu-1"));
+        assert!(!wildcard_matches("eu-?", "eu-10"));
+        assert!(wildcard_matches("*", ""));
+        assert!(wildcard_matches("*a*b", "xxaxxb"));
+        assert!(!wildcard_matches("*a*b", "xxbxxa"));
+    }
+
+    #[test]
+    fn wildcards_ignore_case() {
+        assert!(wildcard_matches("Kick *", "KICK player"));
+        assert!(wildcard_matches("status", "Status"));
+    }
+
+    #[test]
+    fn permissions_apply_to_users_roles_and_servers() {
+        let config = config();
+        assert!(can_execute(&config, &MODERATOR, "eu-1", "kick player"));
+        assert!(can_execute(&config, &MODERATOR, "eu-1", "  status  "));
+        assert!(!can_execute(&config, &MODERATOR, "us-1", "status"));
+        assert!(!can_execute(&config, &MODERATOR, "eu-1", "quit"));
+        assert!(can_execute(&config, &ADMIN, "us-1", "quit"));
+        assert!(!can_execute(&config, &NOBODY, "eu-1", "status"));
+    }
+
+    #[test]
+    fn every_part_of_a_command_needs_permission() {
+        let config = config();
+        assert!(can_execute(
+            &config,
+            &MODERATOR,
+            "eu-1",
+            "status; kick player"
+        ));
+        assert!(can_execute(
+            &config,
+            &MODERATOR,
+            "eu-1",
+            "status;;kick player;"
+        ));
+        assert!(!can_execute(
+            &config,
+            &MODERATOR,
+            "eu-1",
+            "kick player; quit"
+        ));
+        assert!(!can_execute(
+            &config,
+            &MODERATOR,
+            "eu-1",
+            "kick player\nquit"
+        ));
+        assert!(!can_execute(
+            &config,
+            &MODERATOR,
+            "eu-1",
+            "kick player\rquit"
+        ));
+        assert!(can_execute(&config, &ADMIN, "eu-1", "kick player; quit"));
+    }
+
+    #[test]
+    fn seeing_the_audit_log_needs_permission() {
+        let config = config();
+        assert!(check_audit(&config, &ADMIN, None).is_ok());
+        assert!(check_audit(&config, &MODERATOR, Some("eu-1")).is_err());
+        assert_eq!(
+            check_audit(&config, &NOBODY, None),
+            Err("you aren't allowed to see the audit log of **eu-1**".to_string())
+        );
+    }
+
+    #[test]
+    fn anyone_can_execute_anything_without_permissions() {
+        let mut config = config();
+        config.permissions.clear();
+        assert!(can_execute(&config, &NOBODY, "eu-1", "status; quit"));
+    }
+}
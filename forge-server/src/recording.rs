@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail, Result};
+use forge_shared::{ClientPacket, ServerPacket, Target};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A packet in a recording, as one line of JSON. `time` is when it was received or sent, in milliseconds since the
+/// Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Record {
+    Received { time: u64, packet: ClientPacket },
+    Sent { time: u64, packet: ServerPacket },
+}
+
+impl Record {
+    fn time(&self) -> u64 {
+        match self {
+            Record::Received { time, .. } | Record::Sent { time, .. } => *time,
+        }
+    }
+}
+
+/// Writes every packet received from or sent to plugins to a file, so problems can be reproduced by replaying it.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Opens a recording to add packets to, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn received(&self, packet: &ClientPacket) {
+        self.write(&Record::Received {
+            time: now(),
+            packet: packet.clone(),
+        });
+    }
+
+    pub fn sent(&self, packet: &ServerPacket) {
+        self.write(&Record::Sent {
+            time: now(),
+            packet: packet.clone(),
+        });
+    }
+
+    fn write(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).expect("Failed to serialize record");
+        line.push('\n');
+        // Each record is written in one go, so a recording cut short by a crash can still be replayed
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to record packet: {}", err);
+        }
+    }
+}
+
+/// Sends the received packets in a recording, with the time between them divided by `speed`, or no waiting if `speed`
+/// is zero. Sent packets are logged to compare with what's sent during the replay.
+///
+/// Packet timestamps are moved forward to the replay, keeping how late each packet arrived.
+pub async fn replay(
+    path: &Path,
+    speed: f64,
+    client_sender: UnboundedSender<ClientPacket>,
+) -> Result<()> {
+    let file = BufReader::new(File::open(path)?);
+    info!("Replaying {}", path.display());
+
+    let mut last_time = None;
+    for (index, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<Record>(&line)
+            .map_err(|err| anyhow!("invalid record on line {}: {}", index + 1, err))?;
+
+        if let Some(last_time) = last_time {
+            let gap = Duration::from_millis(record.time().saturating_sub(last_time));
+            if speed > 0.0 {
+                tokio::time::sleep(gap.div_f64(speed)).await;
+            }
+        }
+        last_time = Some(record.time());
+
+        match record {
+            Record::Received { time, mut packet } => {
+                let delay = time.saturating_sub(packet.timestamp);
+                packet.timestamp = now().saturating_sub(delay);
+                client_sender
+                    .send(packet)
+                    .expect("Failed to send client packet");
+            }
+            Record::Sent { packet, .. } => match &packet.target {
+                Target::All => info!("Recording sent to every server: {}", packet.event),
+                Target::Names(_) => info!("Recording sent to {}: {}", packet.target, packet.event),
+            },
+        }
+    }
+
+    info!("Replay finished");
+    Ok(())
+}
+
+/// Parses a replay speed multiplier, which has to be finite and can't be negative.
+pub fn parse_speed(speed: &str) -> Result<f64> {
+    let speed: f64 = speed
+        .parse()
+        .map_err(|_| anyhow!("`{}` isn't a number", speed))?;
+    if !speed.is_finite() || speed < 0.0 {
+        bail!("`{}` has to be zero or more, and finite", speed);
+    }
+    Ok(speed)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forge_shared::{ClientEvent, ServerEvent};
+    use std::path::PathBuf;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    /// A recording path that only this test uses, removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "forge-recording-{}-{}.jsonl",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn chat(timestamp: u64) -> ClientPacket {
+        ClientPacket {
+            name: "test".to_string(),
+            sequence: 1,
+            timestamp,
+            event: ClientEvent::ClientChat {
+                name: "player".to_string(),
+                uid: "1".to_string(),
+                message: "hello".to_string(),
+                is_team: false,
+            },
+        }
+    }
+
+    async fn replay_all(path: &Path) -> Vec<ClientPacket> {
+        let (sender, mut receiver) = unbounded_channel();
+        replay(path, 0.0, sender).await.unwrap();
+        let mut packets = Vec::new();
+        while let Ok(packet) = receiver.try_recv() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn records_round_trip() {
+        let path = TempPath::new("round-trip");
+        let recorder = Recorder::open(&path.0).unwrap();
+        let received = chat(now());
+        let sent = ServerPacket {
+            target: Target::Names(vec!["test".to_string()]),
+            event: ServerEvent::ExecCommand {
+                command: "status".to_string(),
+            },
+        };
+        recorder.received(&received);
+        recorder.sent(&sent);
+        drop(recorder);
+
+        let records: Vec<Record> = std::fs::read_to_string(&path.0)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(
+            &records[..],
+            [Record::Received { packet: r, .. }, Record::Sent { packet: s, .. }]
+                if *r == received && *s == sent
+        ));
+
+        // Only received packets are replayed
+        let packets = replay_all(&path.0).await;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].event, received.event);
+    }
+
+    #[tokio::test]
+    async fn shifts_timestamps_to_the_replay() {
+        let path = TempPath::new("timestamps");
+        let records = [
+            // Arrived 250ms after it happened
+            Record::Received {
+                time: 10_250,
+                packet: chat(10_000),
+            },
+            // The game server's clock was ahead, so it can't have arrived early
+            Record::Received {
+                time: 20_000,
+                packet: chat(20_500),
+            },
+        ];
+        let lines: Vec<String> = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect();
+        // Blank lines are skipped
+        std::fs::write(&path.0, lines.join("\n\n")).unwrap();
+
+        let before = now();
+        let packets = replay_all(&path.0).await;
+        let after = now();
+
+        assert_eq!(packets.len(), 2);
+        assert!((before - 250..=after - 250).contains(&packets[0].timestamp));
+        assert!((before..=after).contains(&packets[1].timestamp));
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_lines() {
+        let path = TempPath::new("invalid");
+        std::fs::write(&path.0, "{}\n").unwrap();
+        let (sender, _receiver) = unbounded_channel();
+        let err = replay(&path.0, 0.0, sender).await.unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(parse_speed("0").unwrap(), 0.0);
+        assert_eq!(parse_speed("2.5").unwrap(), 2.5);
+        for speed in ["-1", "inf", "NaN", "fast", ""] {
+            assert!(parse_speed(speed).is_err(), "{:?}", speed);
+        }
+    }
+}
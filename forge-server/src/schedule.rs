@@ -0,0 +1,372 @@
+use crate::config::{LiveConfig, ScheduleAction, ScheduleConfig, ScheduleTime};
+use crate::permissions::guild_controls;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use forge_shared::{ServerEvent, ServerPacket, Target};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+// How often to check for schedules that are due.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A schedule added with `/schedule`, for one server or every server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuntimeSchedule {
+    pub id: u64,
+    pub server: Option<String>,
+    #[serde(flatten)]
+    pub schedule: ScheduleConfig,
+}
+
+/// What's kept in the schedule file. `next_id` is saved too, so the IDs of removed schedules aren't reused.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct ScheduleFile {
+    next_id: u64,
+    schedules: Vec<RuntimeSchedule>,
+}
+
+/// Runs the schedules in the config and those added while running, which are kept in the schedule file if there is
+/// one.
+pub struct Scheduler {
+    config: &'static LiveConfig,
+    path: Option<PathBuf>,
+    schedules: Mutex<ScheduleFile>,
+}
+
+impl Scheduler {
+    /// Loads the schedules added in previous runs, if the schedule file exists.
+    pub fn load(config: &'static LiveConfig) -> Result<Self> {
+        let path = config.get().schedule_file.clone();
+        let mut schedules: ScheduleFile = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            _ => ScheduleFile::default(),
+        };
+        // In case the file was edited by hand
+        let max_id = schedules.schedules.iter().map(|schedule| schedule.id).max();
+        schedules.next_id = schedules.next_id.max(max_id.unwrap_or(0) + 1);
+
+        Ok(Scheduler {
+            config,
+            path,
+            schedules: Mutex::new(schedules),
+        })
+    }
+
+    /// Adds a schedule, returning its ID.
+    pub fn add(&self, server: Option<String>, schedule: ScheduleConfig) -> Result<u64> {
+        if let Some(server) = &server {
+            if !self.config.get().servers.contains_key(server) {
+                bail!("unknown server \"{}\"", server);
+            }
+        }
+        schedule.validate()?;
+
+        let mut schedules = self.schedules.lock().unwrap();
+        let id = schedules.next_id;
+        schedules.next_id += 1;
+        schedules.schedules.push(RuntimeSchedule {
+            id,
+            server,
+            schedule,
+        });
+        if let Err(err) = self.save(&schedules) {
+            schedules.schedules.pop();
+            schedules.next_id = id;
+            return Err(err);
+        }
+
+        Ok(id)
+    }
+
+    /// Finds a schedule added while running by its ID.
+    pub fn get(&self, id: u64) -> Option<RuntimeSchedule> {
+        let schedules = self.schedules.lock().unwrap();
+        schedules
+            .schedules
+            .iter()
+            .find(|schedule| schedule.id == id)
+            .cloned()
+    }
+
+    pub fn remove(&self, id: u64) -> Result<()> {
+        let mut schedules = self.schedules.lock().unwrap();
+        let Some(index) = schedules
+            .schedules
+            .iter()
+            .position(|schedule| schedule.id == id)
+        else {
+            bail!("there's no schedule #{}", id);
+        };
+
+        let removed = schedules.schedules.remove(index);
+        if let Err(err) = self.save(&schedules) {
+            schedules.schedules.insert(index, removed);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Describes the schedules for servers a Discord guild can control, or that can be controlled from outside a guild
+    /// if `guild` is `None`, with Markdown, one per line. Schedules for every server are always included.
+    pub fn describe(&self, guild: Option<u64>) -> String {
+        let config = self.config.get();
+        let controls = |server: Option<&str>| match server {
+            Some(server) => guild_controls(config, guild, server),
+            None => true,
+        };
+        let mut lines = Vec::new();
+
+        for schedule in &config.schedules {
+            lines.push(format!("Config: {}", describe(None, schedule)));
+        }
+        let mut names: Vec<&String> = config.servers.keys().collect();
+        names.sort_unstable();
+        for name in names.into_iter().filter(|name| controls(Some(name))) {
+            for schedule in &config.servers[name].schedules {
+                lines.push(format!("Config: {}", describe(Some(name), schedule)));
+            }
+        }
+        let schedules = self.schedules.lock().unwrap();
+        for schedule in schedules
+            .schedules
+            .iter()
+            .filter(|schedule| controls(schedule.server.as_deref()))
+        {
+            lines.push(format!(
+                "**#{}**: {}",
+                schedule.id,
+                describe(schedule.server.as_deref(), &schedule.schedule)
+            ));
+        }
+
+        if lines.is_empty() {
+            lines.push("No schedules.".to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// Sends the packets for schedules as they come due, forever.
+    pub async fn run(&self, server_sender: UnboundedSender<ServerPacket>) {
+        let mut last_tick = Utc::now();
+        loop {
+            tokio::time::sleep(TICK_INTERVAL).await;
+            let now = Utc::now();
+
+            let mut due = Vec::new();
+            let config = self.config.get();
+            for schedule in &config.schedules {
+                if is_due(schedule, last_tick, now) {
+                    due.push((None, schedule.clone()));
+                }
+            }
+            for (name, server_config) in &config.servers {
+                for schedule in &server_config.schedules {
+                    if is_due(schedule, last_tick, now) {
+                        due.push((Some(name.clone()), schedule.clone()));
+                    }
+                }
+            }
+            for schedule in &self.schedules.lock().unwrap().schedules {
+                if is_due(&schedule.schedule, last_tick, now) {
+                    due.push((schedule.server.clone(), schedule.schedule.clone()));
+                }
+            }
+
+            for (name, schedule) in due {
+                info!("Running schedule: {}", describe(name.as_deref(), &schedule));
+                let event = match schedule.action {
+                    ScheduleAction::Exec(command) => ServerEvent::ExecCommand { command },
+                    ScheduleAction::Announce(message) => ServerEvent::Chat { message },
+                };
+                server_sender
+                    .send(ServerPacket {
+                        target: Target::from_name(name),
+                        event,
+                    })
+                    .expect("Failed to send server packet");
+            }
+
+            last_tick = now;
+        }
+    }
+
+    fn save(&self, schedules: &ScheduleFile) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let json = serde_json::to_string_pretty(schedules)?;
+        std::fs::write(path, json).map_err(|err| {
+            error!("Failed to save schedules to {}: {}", path.display(), err);
+            err.into()
+        })
+    }
+}
+
+/// Checks whether a schedule should run at some point after `last_tick`, up to and including `now`.
+fn is_due(schedule: &ScheduleConfig, last_tick: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    match &schedule.when {
+        ScheduleTime::Cron(expression) => {
+            // Expressions are checked when they're loaded or added, so this shouldn't fail
+            let Ok(cron) = cron::Schedule::from_str(expression) else { return false };
+            matches!(cron.after(&last_tick).next(), Some(next) if next <= now)
+        }
+        ScheduleTime::IntervalMinutes(minutes) => {
+            let interval = *minutes as i64 * 60;
+            interval > 0 && last_tick.timestamp() / interval != now.timestamp() / interval
+        }
+    }
+}
+
+/// Describes a schedule with Markdown, like "every 30 minute(s) on **test**, execute `status`".
+pub fn describe(server: Option<&str>, schedule: &ScheduleConfig) -> String {
+    let when = match &schedule.when {
+        ScheduleTime::Cron(expression) => format!("at `{}`", expression),
+        ScheduleTime::IntervalMinutes(minutes) => format!("every {} minute(s)", minutes),
+    };
+    let action = match &schedule.action {
+        ScheduleAction::Exec(command) => format!("execute `{}`", command),
+        ScheduleAction::Announce(message) => format!("announce \"{}\"", message),
+    };
+    match server {
+        Some(server) => format!("{} on **{}**, {}", when, server, action),
+        None => format!("{} on every server, {}", when, action),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn scheduler() -> Scheduler {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "127.0.0.1:0"
+            discord-token = ""
+            discord-application = 0
+
+            [[schedules]]
+            cron = "0 0 4 * * *"
+            announce = "Restarting soon"
+
+            [[guilds]]
+            id = 1
+            servers = ["test"]
+
+            [servers.test]
+
+            [[servers.test.schedules]]
+            interval-minutes = 30
+            exec = "status"
+
+            [servers.other]
+
+            [[servers.other.schedules]]
+            interval-minutes = 60
+            exec = "status"
+
+            [maps]
+
+            [modes]
+            "#,
+        )
+        .unwrap();
+        let config = Box::leak(Box::new(LiveConfig::new("forge.toml".into(), config)));
+        Scheduler::load(config).unwrap()
+    }
+
+    fn interval(minutes: u64) -> ScheduleConfig {
+        ScheduleConfig {
+            when: ScheduleTime::IntervalMinutes(minutes),
+            action: ScheduleAction::Exec("status".to_string()),
+        }
+    }
+
+    fn time(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn intervals_are_due_when_crossing_a_boundary() {
+        let schedule = interval(30);
+        let due = |last_tick, now| is_due(&schedule, time(last_tick), time(now));
+        assert!(due("2023-01-01T10:29:59Z", "2023-01-01T10:30:00Z"));
+        assert!(due("2023-01-01T10:29:00Z", "2023-01-01T11:01:00Z"));
+        assert!(!due("2023-01-01T10:30:00Z", "2023-01-01T10:30:01Z"));
+        assert!(!due("2023-01-01T10:00:00Z", "2023-01-01T10:29:59Z"));
+        // Counted from the Unix epoch, so 7 minute intervals don't line up with the hour
+        assert!(is_due(
+            &interval(7),
+            time("2023-01-01T10:00:59Z"),
+            time("2023-01-01T10:01:00Z")
+        ));
+        assert!(!is_due(
+            &interval(0),
+            time("2023-01-01T10:29:59Z"),
+            time("2023-01-01T10:30:00Z")
+        ));
+    }
+
+    #[test]
+    fn cron_expressions_are_due_after_the_last_tick() {
+        let schedule = ScheduleConfig {
+            when: ScheduleTime::Cron("0 0 * * * *".to_string()),
+            action: ScheduleAction::Exec("status".to_string()),
+        };
+        let due = |last_tick, now| is_due(&schedule, time(last_tick), time(now));
+        assert!(due("2023-01-01T10:59:59Z", "2023-01-01T11:00:00Z"));
+        assert!(!due("2023-01-01T11:00:00Z", "2023-01-01T11:00:01Z"));
+        assert!(!due("2023-01-01T10:00:01Z", "2023-01-01T10:59:59Z"));
+    }
+
+    #[test]
+    fn removed_ids_are_not_reused() {
+        let scheduler = scheduler();
+        assert_eq!(scheduler.add(None, interval(10)).unwrap(), 1);
+        assert_eq!(scheduler.add(None, interval(20)).unwrap(), 2);
+        scheduler.remove(2).unwrap();
+        assert_eq!(scheduler.add(None, interval(30)).unwrap(), 3);
+        assert!(scheduler.get(2).is_none());
+        assert!(scheduler.remove(2).is_err());
+        assert!(scheduler
+            .add(Some("missing".to_string()), interval(10))
+            .is_err());
+        assert!(scheduler.add(None, interval(0)).is_err());
+    }
+
+    #[test]
+    fn describes_the_schedules_a_guild_controls() {
+        let scheduler = scheduler();
+        scheduler
+            .add(
+                Some("test".to_string()),
+                ScheduleConfig {
+                    when: ScheduleTime::Cron("0 30 * * * *".to_string()),
+                    action: ScheduleAction::Announce("hello".to_string()),
+                },
+            )
+            .unwrap();
+        scheduler
+            .add(Some("other".to_string()), interval(10))
+            .unwrap();
+        scheduler.add(None, interval(20)).unwrap();
+
+        assert_eq!(
+            scheduler.describe(Some(1)),
+            "Config: at `0 0 4 * * *` on every server, announce \"Restarting soon\"\n\
+             Config: every 30 minute(s) on **test**, execute `status`\n\
+             **#1**: at `0 30 * * * *` on **test**, announce \"hello\"\n\
+             **#3**: every 20 minute(s) on every server, execute `status`"
+        );
+        assert_eq!(
+            scheduler.describe(None),
+            "Config: at `0 0 4 * * *` on every server, announce \"Restarting soon\"\n\
+             **#3**: every 20 minute(s) on every server, execute `status`"
+        );
+    }
+}
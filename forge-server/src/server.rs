@@ -1,20 +1,44 @@
-use forge_shared::{serialize, ClientPacket, ReceiveBuffer, ServerPacket};
-use log::{debug, error, info};
+use forge_shared::{
+    serialize, ClientMessage, ClientPacket, ReceiveBuffer, ServerMessage, ServerPacket,
+    PROTOCOL_VERSION,
+};
+use log::{debug, error, info, warn};
 use serenity::futures::future::join_all;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 
+// How long a write to a client can take before the client is disconnected. Writes happen while every stream is
+// locked, so one stalled client would otherwise hold up everything else.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Streams = Mutex<Vec<Stream>>;
+type Sessions = Mutex<HashMap<String, Session>>;
+
+// Tracks the last packet received from each client name, across reconnects.
+struct Session {
+    id: u64,
+    last_sequence: u64,
+}
+
 struct Stream {
     id: u64,
+    addr: SocketAddr,
+    name: Option<String>,
     write: OwnedWriteHalf,
     read: JoinHandle<()>,
+
+    last_received: Instant,
+    last_ping: Option<(u64, Instant)>,
+    latency: Option<Duration>,
 }
 
 impl Drop for Stream {
@@ -23,10 +47,21 @@ impl Drop for Stream {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+    /// Round-trip time of the last heartbeat.
+    pub latency: Option<Duration>,
+}
+
 pub struct Server {
     next_id: AtomicU64,
+    next_nonce: AtomicU64,
     listener: TcpListener,
-    streams: Arc<Mutex<Vec<Stream>>>,
+    streams: Arc<Streams>,
+    sessions: Arc<Sessions>,
+    decode_errors: Arc<AtomicU64>,
 }
 
 impl Server {
@@ -34,8 +69,11 @@ impl Server {
         let listener = TcpListener::bind(addr).await?;
         Ok(Server {
             next_id: AtomicU64::new(0),
+            next_nonce: AtomicU64::new(0),
             listener,
             streams: Arc::new(Mutex::new(Vec::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            decode_errors: Arc::new(AtomicU64::new(0)),
         })
     }
 
@@ -54,19 +92,33 @@ impl Server {
             };
             debug!("Connection from {addr}");
 
+            if let Err(err) = socket.set_nodelay(true) {
+                error!("{addr} failed to set TCP_NODELAY: {err}");
+            }
+
             let (read_half, write_half) = socket.into_split();
 
             let stream_id = self.next_id.fetch_add(1, Ordering::AcqRel);
 
-            let streams = Arc::downgrade(&self.streams);
+            // Hold the lock until the stream is pushed, so the read loop can always find it
+            let mut streams = self.streams.lock().await;
+
+            let weak_streams = Arc::downgrade(&self.streams);
+            let sessions = self.sessions.clone();
+            let decode_errors = self.decode_errors.clone();
             let sender = sender.clone();
 
             let read = tokio::spawn(async move {
-                if let Err(err) = stream_read_loop(read_half, sender).await {
+                if let Err(err) =
+                    stream_read_loop(stream_id, read_half, &weak_streams, &sessions, sender).await
+                {
                     error!("{addr} read error: {err}");
+                    if err.kind() == std::io::ErrorKind::InvalidData {
+                        decode_errors.fetch_add(1, Ordering::Relaxed);
+                    }
 
                     // Remove the error stream
-                    if let Some(streams) = streams.upgrade() {
+                    if let Some(streams) = weak_streams.upgrade() {
                         let mut streams = streams.lock().await;
                         streams.retain(|write| write.id != stream_id);
                         info!("{} client(s) connected", streams.len());
@@ -74,75 +126,243 @@ impl Server {
                 }
             });
 
-            self.push_stream(stream_id, write_half, read).await;
+            streams.push(Stream {
+                id: stream_id,
+                addr,
+                name: None,
+                write: write_half,
+                read,
+                last_received: Instant::now(),
+                last_ping: None,
+                latency: None,
+            });
+            info!("{} client(s) connected", streams.len());
         }
     }
 
     pub async fn send(&self, packet: &ServerPacket) {
-        debug!(
-            "OUT ({}) {}",
-            packet
-                .name
-                .as_ref()
-                .map(|name| name as &str)
-                .unwrap_or("<everyone>"),
-            packet.event
-        );
-        let serialized = serialize(packet);
+        debug!("OUT ({}) {}", packet.target, packet.event);
+        let serialized = serialize(&ServerMessage::Packet(packet.clone()));
 
         let mut streams = self.streams.lock().await;
+        write_all_streams(&mut streams, &serialized).await;
+    }
 
-        let results = join_all(
-            streams
-                .iter_mut()
-                .map(|stream| stream.write.write_all(&serialized)),
-        )
-        .await;
-
-        // Remove any streams that had write errors
-        let mut index = 0;
-        streams.retain(|write_half| {
-            let res = &results[index];
-            index += 1;
-
-            if let Err(err) = res {
-                error!(
-                    "{} write error: {}",
-                    write_half.write.local_addr().unwrap(),
-                    err
-                );
+    /// Pings every connected client each `interval`, and disconnects clients that haven't sent
+    /// anything for `timeout`.
+    pub async fn heartbeat(&self, interval: Duration, timeout: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let mut streams = self.streams.lock().await;
+            let stream_count = streams.len();
+            streams.retain(|stream| {
+                let silence = stream.last_received.elapsed();
+                if silence >= timeout {
+                    warn!(
+                        "{} timed out after {}s without a response",
+                        stream.addr,
+                        silence.as_secs()
+                    );
+                }
+                silence < timeout
+            });
+            if streams.len() != stream_count {
+                info!("{} client(s) connected", streams.len());
             }
 
-            res.is_ok()
-        });
+            let nonce = self.next_nonce.fetch_add(1, Ordering::AcqRel);
+            let sent_at = Instant::now();
+            for stream in streams.iter_mut() {
+                stream.last_ping = Some((nonce, sent_at));
+            }
 
-        if streams.len() != results.len() {
-            info!("{} client(s) connected", streams.len());
+            write_all_streams(&mut streams, &serialize(&ServerMessage::Ping { nonce })).await;
         }
     }
 
-    async fn push_stream(&self, id: u64, write: OwnedWriteHalf, read: JoinHandle<()>) {
-        let mut streams = self.streams.lock().await;
-        streams.push(Stream { id, write, read });
+    /// Number of connections dropped because they sent something that couldn't be decoded, or
+    /// didn't follow the protocol.
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        let streams = self.streams.lock().await;
+        streams
+            .iter()
+            .map(|stream| ConnectionInfo {
+                addr: stream.addr,
+                name: stream.name.clone(),
+                latency: stream.latency,
+            })
+            .collect()
+    }
+}
+
+/// Writes the same data to every stream, removing any streams that fail.
+async fn write_all_streams(streams: &mut Vec<Stream>, data: &[u8]) {
+    let results = join_all(
+        streams
+            .iter_mut()
+            .map(|stream| write_with_timeout(&mut stream.write, data)),
+    )
+    .await;
+
+    // Remove any streams that had write errors
+    let mut index = 0;
+    streams.retain(|write_half| {
+        let res = &results[index];
+        index += 1;
+
+        if let Err(err) = res {
+            error!(
+                "{} write error: {}",
+                write_half.write.local_addr().unwrap(),
+                err
+            );
+        }
+
+        res.is_ok()
+    });
+
+    if streams.len() != results.len() {
         info!("{} client(s) connected", streams.len());
     }
 }
 
+async fn write_with_timeout(write: &mut OwnedWriteHalf, data: &[u8]) -> std::io::Result<()> {
+    match tokio::time::timeout(WRITE_TIMEOUT, write.write_all(data)).await {
+        Ok(res) => res,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "write timed out",
+        )),
+    }
+}
+
 async fn stream_read_loop(
+    id: u64,
     mut read_half: OwnedReadHalf,
+    streams: &Weak<Streams>,
+    sessions: &Sessions,
     sender: UnboundedSender<ClientPacket>,
 ) -> std::io::Result<()> {
-    let mut buffer = ReceiveBuffer::new(|packet: ClientPacket| {
-        debug!("IN ({}) {}", packet.name, packet.event);
-        sender.send(packet).expect("Failed to send packet");
+    // Messages are handled outside of the buffer callback, since some need to be awaited
+    let (message_sender, mut message_receiver) = unbounded_channel();
+    let mut buffer = ReceiveBuffer::new(|message: ClientMessage| {
+        message_sender.send(message).expect("Failed to queue message");
     });
 
+    let mut client_name = None;
+
     loop {
         let mut read = buffer.start_read();
         let write_len = read_half.read(read.data()).await?;
         if write_len == 0 {
             return Err(std::io::ErrorKind::UnexpectedEof.into())
         }
-        read.finish(write_len);
+        read.finish(write_len)?;
+
+        let mut ack_sequence = None;
+        let mut pong_nonce = None;
+        let mut reply = Vec::new();
+        while let Ok(message) = message_receiver.try_recv() {
+            match message {
+                ClientMessage::Hello {
+                    name,
+                    version,
+                    session,
+                } => {
+                    if version != PROTOCOL_VERSION {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("\"{name}\" uses unsupported protocol version {version}"),
+                        ));
+                    }
+
+                    info!("{} identified as \"{name}\"", read_half.peer_addr()?);
+
+                    let mut sessions = sessions.lock().await;
+                    let is_new_session = sessions
+                        .get(&name)
+                        .map(|existing| existing.id != session)
+                        .unwrap_or(true);
+                    if is_new_session {
+                        debug!("New session for \"{name}\"");
+                        sessions.insert(
+                            name.clone(),
+                            Session {
+                                id: session,
+                                last_sequence: 0,
+                            },
+                        );
+                    }
+                    drop(sessions);
+
+                    let Some(streams) = streams.upgrade() else { return Ok(()) };
+                    let mut streams = streams.lock().await;
+                    let Some(stream) = streams.iter_mut().find(|stream| stream.id == id) else { return Ok(()) };
+
+                    stream.name = Some(name.clone());
+                    write_with_timeout(&mut stream.write, &serialize(&ServerMessage::Welcome)).await?;
+                    client_name = Some(name);
+                }
+                ClientMessage::Packet(packet) => {
+                    let Some(name) = &client_name else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "packet received before handshake",
+                        ));
+                    };
+                    // Otherwise one server could pretend to be another
+                    if packet.name != *name {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("\"{name}\" sent a packet as \"{}\"", packet.name),
+                        ));
+                    }
+
+                    ack_sequence = Some(packet.sequence);
+
+                    let mut sessions = sessions.lock().await;
+                    let session = sessions
+                        .get_mut(name)
+                        .expect("Session missing after handshake");
+                    if packet.sequence <= session.last_sequence {
+                        debug!("Dropping duplicate ({}) #{}", packet.name, packet.sequence);
+                        continue;
+                    }
+                    session.last_sequence = packet.sequence;
+                    drop(sessions);
+
+                    debug!("IN ({}) {}", packet.name, packet.event);
+                    sender.send(packet).expect("Failed to send packet");
+                }
+                ClientMessage::Ping { nonce } => {
+                    reply.extend(serialize(&ServerMessage::Pong { nonce }));
+                }
+                ClientMessage::Pong { nonce } => pong_nonce = Some(nonce),
+            }
+        }
+
+        if let Some(sequence) = ack_sequence {
+            reply.extend(serialize(&ServerMessage::Ack { sequence }));
+        }
+
+        let Some(streams) = streams.upgrade() else { return Ok(()) };
+        let mut streams = streams.lock().await;
+        let Some(stream) = streams.iter_mut().find(|stream| stream.id == id) else { return Ok(()) };
+
+        stream.last_received = Instant::now();
+        if let (Some(nonce), Some((ping_nonce, sent_at))) = (pong_nonce, stream.last_ping) {
+            if nonce == ping_nonce {
+                stream.latency = Some(sent_at.elapsed());
+            }
+        }
+        if !reply.is_empty() {
+            write_with_timeout(&mut stream.write, &reply).await?;
+        }
     }
 }
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use log::info;
+use serenity::async_trait;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+/// A message rendered from a client event, ready to be displayed by a [`Sink`].
+///
+/// Text is formatted with Markdown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A chat message. `sent_at` is set, in milliseconds since the Unix epoch, when it's being displayed long after
+    /// it was sent.
+    Chat { text: String, sent_at: Option<u64> },
+    /// Something that happened on the server, such as a player joining. `category` is anything but
+    /// [`Category::Chat`] or [`Category::Warnings`], and `timestamp` is in milliseconds since the Unix epoch.
+    Event {
+        category: Category,
+        description: String,
+        timestamp: u64,
+    },
+    /// A problem with the relay itself, such as lost events.
+    Warning { description: String },
+}
+
+/// What a message is about, which decides where it's displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Chat,
+    /// Players joining and leaving.
+    Joins,
+    /// Matches starting.
+    Matches,
+    Warnings,
+    /// Actions taken from Discord, like executing commands or adding schedules, from the audit log.
+    Moderation,
+    /// Commands executed on the server.
+    CommandOutput,
+}
+
+impl Message {
+    pub fn category(&self) -> Category {
+        match self {
+            Message::Chat { .. } => Category::Chat,
+            Message::Event { category, .. } => *category,
+            Message::Warning { .. } => Category::Warnings,
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Chat { text, .. } => write!(f, "{}", text),
+            Message::Event { description, .. } => write!(f, "{}", description),
+            Message::Warning { description } => write!(f, "Warning: {}", description),
+        }
+    }
+}
+
+/// Formats a Unix timestamp in milliseconds as a UTC time of day.
+pub fn format_time(timestamp: u64) -> String {
+    let seconds = timestamp / 1000 % (24 * 60 * 60);
+    format!(
+        "{:02}:{:02}:{:02} UTC",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Somewhere messages for each server are displayed.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Displays a message from the server with the given name.
+    async fn send(&self, server: &str, message: &Message) -> Result<()>;
+}
+
+/// A sink that keeps every message in memory instead of displaying it.
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    messages: Mutex<Vec<(String, Message)>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns every message recorded so far along with the server it was for, and clears the recording.
+    pub fn take(&self) -> Vec<(String, Message)> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl Sink for RecordingSink {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((server.to_string(), message.clone()));
+        Ok(())
+    }
+}
+
+/// A sink that logs every message instead of displaying it.
+#[derive(Debug, Default)]
+pub struct LogSink;
+
+#[async_trait]
+impl Sink for LogSink {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        info!("[{}] {}", server, message);
+        Ok(())
+    }
+}
+
+/// Displays every message on each of a list of sinks.
+pub struct Sinks<'a>(pub Vec<&'a dyn Sink>);
+
+#[async_trait]
+impl Sink for Sinks<'_> {
+    async fn send(&self, server: &str, message: &Message) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &self.0 {
+            if let Err(err) = sink.send(server, message).await {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::Config;
+use crate::server::Server;
+use forge_shared::{ClientEvent, ClientPacket};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+/// What's known about a game server from the events it has sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerState {
+    pub map: Option<String>,
+    pub mode: Option<String>,
+    pub players: Vec<Player>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub name: String,
+    pub uid: String,
+}
+
+/// A configured or connected server, and what's known about it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub connected: bool,
+    pub latency_ms: Option<u64>,
+    #[serde(flatten)]
+    pub state: ServerState,
+}
+
+/// Tracks the map, mode and players of every server.
+#[derive(Debug, Default)]
+pub struct States {
+    states: Mutex<HashMap<String, ServerState>>,
+}
+
+impl States {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn update(&self, packet: &ClientPacket) {
+        let mut states = self.states.lock().await;
+        let state = states.entry(packet.name.clone()).or_default();
+
+        match &packet.event {
+            ClientEvent::GameStart { map, mode } => {
+                // Players connect again for each match, so the roster starts over
+                state.map = Some(map.clone());
+                state.mode = Some(mode.clone());
+                state.players.clear();
+            }
+            ClientEvent::ClientConnecting { name, uid } => {
+                state.players.retain(|player| player.uid != *uid);
+                state.players.push(Player {
+                    name: name.clone(),
+                    uid: uid.clone(),
+                });
+            }
+            ClientEvent::ClientDisconnected { uid, .. } => {
+                state.players.retain(|player| player.uid != *uid);
+            }
+            ClientEvent::ClientChat { .. } => {}
+        }
+    }
+
+    /// Returns the number of players on each server.
+    pub async fn player_counts(&self) -> Vec<(String, usize)> {
+        self.states
+            .lock()
+            .await
+            .iter()
+            .map(|(name, state)| (name.clone(), state.players.len()))
+            .collect()
+    }
+
+    pub async fn get(&self, name: &str) -> ServerState {
+        self.states
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns info about a server, or `None` if it's neither configured nor connected.
+    pub async fn server_info(
+        &self,
+        config: &Config,
+        server: &Server,
+        name: &str,
+    ) -> Option<ServerInfo> {
+        let connection = server
+            .connections()
+            .await
+            .into_iter()
+            .find(|connection| connection.name.as_deref() == Some(name));
+        if connection.is_none() && !config.servers.contains_key(name) {
+            return None;
+        }
+
+        Some(ServerInfo {
+            name: name.to_string(),
+            connected: connection.is_some(),
+            latency_ms: connection
+                .and_then(|connection| connection.latency)
+                .map(|latency| latency.as_millis() as u64),
+            state: self.get(name).await,
+        })
+    }
+
+    /// Returns info about every configured or connected server, sorted by name.
+    pub async fn server_infos(&self, config: &Config, server: &Server) -> Vec<ServerInfo> {
+        let connections = server.connections().await;
+
+        let mut names: Vec<&str> = config.servers.keys().map(|name| name as &str).collect();
+        for connection in &connections {
+            if let Some(name) = &connection.name {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort_unstable();
+
+        let mut servers = Vec::new();
+        for name in names {
+            servers.extend(self.server_info(config, server, name).await);
+        }
+        servers
+    }
+}
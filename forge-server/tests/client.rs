@@ -0,0 +1,228 @@
+use forge_server::server::Server;
+use forge_shared::client::{Backoff, Client, ClientConfig, ClientHandle, ReconnectPolicy};
+use forge_shared::{
+    serialize, ClientEvent, ClientMessage, ClientPacket, ReceiveBuffer, ServerEvent, ServerMessage,
+    ServerPacket, Target, PROTOCOL_VERSION,
+};
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn start_server() -> (&'static Server, UnboundedReceiver<ClientPacket>) {
+    let server = Server::new(([127, 0, 0, 1], 0).into()).await.unwrap();
+    let server: &'static Server = Box::leak(Box::new(server));
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(server.receive(sender));
+    (server, receiver)
+}
+
+fn start_client(
+    server: &Server,
+    name: &str,
+) -> (
+    ClientHandle,
+    mpsc::Receiver<ServerPacket>,
+    std::thread::JoinHandle<()>,
+) {
+    let mut config = ClientConfig::new(name, server.local_addr().unwrap());
+    config.reconnect = ReconnectPolicy::Backoff(Backoff {
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(200),
+        jitter: 0.,
+    });
+    let (client, handle) = Client::new(config);
+
+    let (packet_sender, packet_receiver) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+        client.run(|packet| {
+            let _ = packet_sender.send(packet);
+        })
+    });
+    (handle, packet_receiver, thread)
+}
+
+fn chat(message: &str) -> ClientEvent {
+    ClientEvent::ClientChat {
+        name: "player".to_string(),
+        uid: "1".to_string(),
+        message: message.to_string(),
+        is_team: false,
+    }
+}
+
+async fn next_packet(receiver: &mut UnboundedReceiver<ClientPacket>) -> ClientPacket {
+    tokio::time::timeout(TIMEOUT, receiver.recv())
+        .await
+        .expect("Timed out waiting for a packet")
+        .expect("Server stopped")
+}
+
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "Timed out waiting");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+async fn wait_for_name(server: &Server, name: &str) {
+    let start = Instant::now();
+    loop {
+        let connections = server.connections().await;
+        if connections
+            .iter()
+            .any(|connection| connection.name.as_deref() == Some(name))
+        {
+            return;
+        }
+        assert!(
+            start.elapsed() < TIMEOUT,
+            "Timed out waiting for \"{}\"",
+            name
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_and_receives_packets() {
+    let (server, mut receiver) = start_server().await;
+    let (handle, packets, thread) = start_client(server, "test");
+
+    // The client only gets a name on the server once it has said hello and been welcomed
+    wait_for_name(server, "test").await;
+
+    handle.send(chat("hello"));
+    let packet = next_packet(&mut receiver).await;
+    assert_eq!(packet.name, "test");
+    assert_eq!(packet.sequence, 1);
+    assert_eq!(packet.event, chat("hello"));
+
+    // Acknowledged events are removed from the queue
+    wait_until(|| handle.queued() == 0).await;
+
+    server
+        .send(&ServerPacket {
+            target: Target::name("test"),
+            event: ServerEvent::ExecCommand {
+                command: "status".to_string(),
+            },
+        })
+        .await;
+    server
+        .send(&ServerPacket {
+            target: Target::name("other"),
+            event: ServerEvent::ExecCommand {
+                command: "quit".to_string(),
+            },
+        })
+        .await;
+    let packet = packets.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(
+        packet.event,
+        ServerEvent::ExecCommand {
+            command: "status".to_string()
+        }
+    );
+    assert!(packets.recv_timeout(Duration::from_millis(200)).is_err());
+
+    handle.shutdown();
+    thread.join().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_after_disconnecting() {
+    let (server, mut receiver) = start_server().await;
+    let (handle, _packets, thread) = start_client(server, "test");
+    wait_for_name(server, "test").await;
+    let first_addr = server.connections().await[0].addr;
+
+    handle.send(chat("one"));
+    assert_eq!(next_packet(&mut receiver).await.sequence, 1);
+    wait_until(|| handle.queued() == 0).await;
+
+    handle.disconnect();
+    // The new connection comes from a different port, and says hello again
+    let start = Instant::now();
+    loop {
+        let connections = server.connections().await;
+        let reconnected = connections.iter().any(|connection| {
+            connection.addr != first_addr && connection.name.as_deref() == Some("test")
+        });
+        if reconnected {
+            break;
+        }
+        assert!(start.elapsed() < TIMEOUT, "Timed out waiting to reconnect");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Sequence numbers carry on, and acknowledged events aren't sent again
+    handle.send(chat("two"));
+    let packet = next_packet(&mut receiver).await;
+    assert_eq!(packet.sequence, 2);
+    assert_eq!(packet.event, chat("two"));
+    wait_until(|| handle.queued() == 0).await;
+
+    handle.shutdown();
+    thread.join().unwrap();
+}
+
+#[test]
+fn resends_unacknowledged_events_after_reconnecting() {
+    // A server that welcomes the client but never acknowledges anything
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = ClientConfig::new("test", listener.local_addr().unwrap());
+    config.reconnect = ReconnectPolicy::Backoff(Backoff {
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(200),
+        jitter: 0.,
+    });
+    let (client, handle) = Client::new(config);
+    handle.send(chat("one"));
+    let thread = std::thread::spawn(move || client.run(|_| {}));
+
+    let mut sessions = Vec::new();
+    for _ in 0..2 {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let (message_sender, messages) = mpsc::channel();
+        let mut buffer = ReceiveBuffer::new(|message: ClientMessage| {
+            message_sender.send(message).unwrap();
+        });
+
+        let mut sequences = Vec::new();
+        let mut session = None;
+        while sequences.is_empty() {
+            buffer.read(&mut stream).unwrap();
+            for message in messages.try_iter() {
+                match message {
+                    ClientMessage::Hello {
+                        version,
+                        session: id,
+                        ..
+                    } => {
+                        assert_eq!(version, PROTOCOL_VERSION);
+                        session = Some(id);
+                        stream
+                            .write_all(&serialize(&ServerMessage::Welcome))
+                            .unwrap();
+                    }
+                    ClientMessage::Packet(packet) => sequences.push(packet.sequence),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(sequences, vec![1]);
+        sessions.push(session.expect("Packet sent before hello"));
+    }
+    // Both connections belong to the same session, so the server can drop the duplicate
+    assert_eq!(sessions[0], sessions[1]);
+    assert_eq!(handle.queued(), 1);
+
+    handle.shutdown();
+    thread.join().unwrap();
+}
@@ -0,0 +1,152 @@
+use forge_server::config::{Config, LiveConfig};
+use forge_server::display::run_client_display_loop;
+use forge_server::server::Server;
+use forge_server::sink::{Category, Message, RecordingSink};
+use forge_shared::{
+    serialize, ClientEvent, ClientMessage, ClientPacket, ReceiveBuffer, ServerMessage,
+    PROTOCOL_VERSION,
+};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::unbounded_channel;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        listen = "127.0.0.1:0"
+        discord-token = ""
+        discord-application = 0
+
+        [servers.test]
+
+        [maps]
+        mp_glitch = "Glitch"
+
+        [modes]
+        aitdm = "Attrition"
+        "#,
+    )
+    .unwrap()
+}
+
+/// Connects to the server as `name` and waits to be welcomed.
+fn connect(server: &Server, name: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream
+        .write_all(&serialize(&ClientMessage::Hello {
+            name: name.to_string(),
+            version: PROTOCOL_VERSION,
+            session: 1,
+        }))
+        .unwrap();
+
+    let (message_sender, messages) = mpsc::channel();
+    let mut buffer = ReceiveBuffer::new(|message: ServerMessage| {
+        message_sender.send(message).unwrap();
+    });
+    loop {
+        buffer.read(&mut stream).unwrap();
+        if messages
+            .try_iter()
+            .any(|message| matches!(message, ServerMessage::Welcome))
+        {
+            return stream;
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, sequence: u64, event: ClientEvent) {
+    let packet = ClientPacket {
+        name: "test".to_string(),
+        sequence,
+        timestamp: 1000,
+        event,
+    };
+    stream
+        .write_all(&serialize(&ClientMessage::Packet(packet)))
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn displays_packets_from_clients() {
+    let server = Server::new(([127, 0, 0, 1], 0).into()).await.unwrap();
+    let server: &'static Server = Box::leak(Box::new(server));
+    let config: &'static LiveConfig =
+        Box::leak(Box::new(LiveConfig::new("forge.toml".into(), config())));
+    let sink: &'static RecordingSink = Box::leak(Box::new(RecordingSink::new()));
+
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(server.receive(sender));
+    tokio::spawn(run_client_display_loop(config, sink, receiver));
+
+    let stream = tokio::task::spawn_blocking(move || {
+        let mut stream = connect(server, "test");
+        send(
+            &mut stream,
+            1,
+            ClientEvent::GameStart {
+                map: "mp_glitch".to_string(),
+                mode: "aitdm".to_string(),
+            },
+        );
+        // Sequences 2 and 3 never arrive
+        send(
+            &mut stream,
+            4,
+            ClientEvent::ClientChat {
+                name: "player".to_string(),
+                uid: "1".to_string(),
+                message: "hello".to_string(),
+                is_team: false,
+            },
+        );
+        stream
+    })
+    .await
+    .unwrap();
+
+    let mut messages = Vec::new();
+    let start = Instant::now();
+    while messages.len() < 3 {
+        assert!(start.elapsed() < TIMEOUT, "Timed out waiting for messages");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        messages.extend(sink.take());
+    }
+
+    let server_name = "test".to_string();
+    assert_eq!(
+        messages,
+        vec![
+            (
+                server_name.clone(),
+                Message::Event {
+                    category: Category::Matches,
+                    description: "Starting **Attrition** on **Glitch**.".to_string(),
+                    timestamp: 1000,
+                }
+            ),
+            (
+                server_name.clone(),
+                Message::Warning {
+                    description: "2 event(s) were lost.".to_string()
+                }
+            ),
+            (
+                server_name,
+                Message::Chat {
+                    text: "**player**: hello".to_string(),
+                    // Sent at 1000ms after the epoch, so long ago
+                    sent_at: Some(1000),
+                }
+            ),
+        ]
+    );
+
+    // Keep the connection open until the messages have been checked
+    drop(stream);
+}
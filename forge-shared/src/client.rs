@@ -0,0 +1,562 @@
+use crate::{
+    serialize, ClientEvent, ClientMessage, ClientPacket, ReceiveBuffer, ServerMessage,
+    ServerPacket, PROTOCOL_VERSION,
+};
+use log::{debug, error, info, warn};
+use queue::OutgoingQueue;
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod queue;
+
+pub use queue::{OverflowPolicy, QueueConfig};
+
+// How often blocking waits wake up to check if the client has been shut down.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Minimum time between logs about repeated connection failures.
+const FAILURE_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub name: String,
+    pub remote: SocketAddr,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub reconnect: ReconnectPolicy,
+    pub queue: QueueConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+impl ClientConfig {
+    pub fn new(name: impl Into<String>, remote: SocketAddr) -> Self {
+        ClientConfig {
+            name: name.into(),
+            remote,
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            reconnect: ReconnectPolicy::Backoff(Backoff::default()),
+            queue: QueueConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often to ping the server.
+    pub interval: Duration,
+    /// How long the server can be silent before the connection is considered dead.
+    pub timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    /// Checks that pings are sent at all, and that the connection isn't considered dead before the
+    /// reply to a ping can arrive.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.interval.is_zero() {
+            return Err("the heartbeat interval can't be zero");
+        }
+        if self.timeout <= self.interval {
+            return Err("the heartbeat timeout has to be longer than the interval");
+        }
+        Ok(())
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconnectPolicy {
+    /// Stop running once the first connection is closed or fails.
+    Never,
+    /// Try again after a delay that grows with each consecutive failure.
+    Backoff(Backoff),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay, from 0 to 1, that is randomly taken off so clients don't retry in
+    /// lockstep.
+    pub jitter: f64,
+}
+
+impl Backoff {
+    /// Checks that there's a delay between attempts at all, that it can grow, and that the jitter
+    /// is a fraction.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.initial_delay.is_zero() {
+            return Err("the initial reconnect delay can't be zero");
+        }
+        if self.max_delay < self.initial_delay {
+            return Err("the maximum reconnect delay can't be shorter than the initial delay");
+        }
+        if !(0. ..=1.).contains(&self.jitter) {
+            return Err("the reconnect jitter has to be from 0 to 1");
+        }
+        Ok(())
+    }
+
+    /// The delay before the next attempt, after `failures` consecutive failed attempts.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let multiplier = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self
+            .initial_delay
+            .saturating_mul(multiplier)
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0., 1.) * rand::random::<f64>();
+        delay.mul_f64(1. - jitter)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.25,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    is_shutdown: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+    queue: Mutex<OutgoingQueue>,
+    queue_changed: Condvar,
+    latency: Mutex<Option<Duration>>,
+}
+
+impl Shared {
+    fn is_shutdown(&self) -> bool {
+        self.is_shutdown.load(Ordering::Acquire)
+    }
+
+    fn close_stream(&self) {
+        if let Some(stream) = self.stream.lock().unwrap().as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A cloneable handle used to queue events and stop a running [`Client`].
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    /// Queues an event to be sent the next time the client is connected.
+    ///
+    /// The event stays queued until the server acknowledges it, so it survives reconnects.
+    pub fn send(&self, event: ClientEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        if let Some(dropped) = queue.push(event, timestamp) {
+            warn!(
+                "Queue is full ({} events), dropped {}",
+                queue.len(),
+                dropped.event
+            );
+        }
+        self.shared.queue_changed.notify_all();
+    }
+
+    /// Number of events that haven't been acknowledged by the server yet.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+
+    /// Closes the current connection without stopping the client, so it reconnects according to
+    /// its [`ReconnectPolicy`].
+    pub fn disconnect(&self) {
+        self.shared.close_stream();
+    }
+
+    /// Closes the current connection and makes [`Client::run`] return.
+    pub fn shutdown(&self) {
+        self.shared.is_shutdown.store(true, Ordering::Release);
+        self.shared.close_stream();
+        self.shared.queue_changed.notify_all();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.is_shutdown()
+    }
+
+    /// Round-trip time of the last heartbeat, if the client is connected.
+    pub fn latency(&self) -> Option<Duration> {
+        *self.shared.latency.lock().unwrap()
+    }
+}
+
+// State for a single connection, shared between its read and send threads.
+#[derive(Debug)]
+struct Connection {
+    is_closed: AtomicBool,
+    last_received: Mutex<Instant>,
+    last_ping: Mutex<Option<(u64, Instant)>>,
+    pending_pong: Mutex<Option<u64>>,
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Acquire)
+    }
+
+    fn close(&self) {
+        self.is_closed.store(true, Ordering::Release);
+    }
+}
+
+/// A blocking connection to a Forge server, which reconnects according to its [`ReconnectPolicy`].
+#[derive(Debug)]
+pub struct Client {
+    config: ClientConfig,
+    session: u64,
+    shared: Arc<Shared>,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> (Client, ClientHandle) {
+        let shared = Arc::new(Shared {
+            is_shutdown: AtomicBool::new(false),
+            stream: Mutex::new(None),
+            queue: Mutex::new(OutgoingQueue::new(config.queue)),
+            queue_changed: Condvar::new(),
+            latency: Mutex::new(None),
+        });
+
+        let client = Client {
+            config,
+            session: rand::random(),
+            shared: shared.clone(),
+        };
+        let handle = ClientHandle { shared };
+        (client, handle)
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Connects to the server and runs until the client is shut down, or until the connection is
+    /// lost and the reconnect policy doesn't allow another attempt.
+    ///
+    /// `on_packet` is called from a separate thread for every packet addressed to this client.
+    pub fn run<F: FnMut(ServerPacket) + Send>(&self, mut on_packet: F) {
+        let mut failures = 0;
+        let mut last_failure_log: Option<Instant> = None;
+
+        while !self.shared.is_shutdown() {
+            if failures == 0 {
+                info!("Connecting to {}", self.config.remote);
+            } else {
+                debug!("Connecting to {} (attempt {})", self.config.remote, failures + 1);
+            }
+
+            let res =
+                TcpStream::connect_timeout(&self.config.remote, self.config.connect_timeout)
+                    .and_then(|stream| self.run_connection(stream, &mut on_packet));
+
+            match res {
+                Ok(()) => failures = 0,
+                Err(_) if self.shared.is_shutdown() => break,
+                Err(err) => {
+                    failures += 1;
+
+                    let should_log = last_failure_log
+                        .map(|time| failures == 1 || time.elapsed() >= FAILURE_LOG_INTERVAL)
+                        .unwrap_or(true);
+                    if should_log {
+                        error!("Failed to connect ({} attempt(s)): {}", failures, err);
+                        last_failure_log = Some(Instant::now());
+                    }
+                }
+            }
+
+            if !self.wait_reconnect(failures) {
+                break;
+            }
+        }
+    }
+
+    /// Runs a connection until it closes. Returns an error if the connection couldn't be
+    /// established.
+    fn run_connection<F: FnMut(ServerPacket) + Send>(
+        &self,
+        mut stream: TcpStream,
+        on_packet: &mut F,
+    ) -> std::io::Result<()> {
+        let mut recv_stream = stream.try_clone()?;
+        *self.shared.stream.lock().unwrap() = Some(stream.try_clone()?);
+
+        // The handle may have been shut down before the stream was stored
+        if self.shared.is_shutdown() {
+            self.shared.stream.lock().unwrap().take();
+            return Ok(());
+        }
+
+        // Wake up regularly to check if the server has gone silent
+        recv_stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(self.config.heartbeat.timeout))?;
+        stream.set_nodelay(true)?;
+
+        let connection = Connection {
+            is_closed: AtomicBool::new(false),
+            last_received: Mutex::new(Instant::now()),
+            last_ping: Mutex::new(None),
+            pending_pong: Mutex::new(None),
+        };
+        let (welcome_sender, welcome_receiver) = channel();
+
+        let res = std::thread::scope(|s| {
+            let config = &self.config;
+            let shared = &self.shared;
+            let connection = &connection;
+
+            s.spawn(move || {
+                let mut buffer = ReceiveBuffer::new(|message: ServerMessage| {
+                    *connection.last_received.lock().unwrap() = Instant::now();
+
+                    match message {
+                        ServerMessage::Welcome => {
+                            let _ = welcome_sender.send(());
+                        }
+                        ServerMessage::Ack { sequence } => {
+                            shared.queue.lock().unwrap().ack(sequence);
+                        }
+                        ServerMessage::Packet(packet) => {
+                            if !packet.target.includes(&config.name) {
+                                return;
+                            }
+
+                            info!("IN {}", packet.event);
+                            on_packet(packet);
+                        }
+                        ServerMessage::Ping { nonce } => {
+                            *connection.pending_pong.lock().unwrap() = Some(nonce);
+                            shared.queue_changed.notify_all();
+                        }
+                        ServerMessage::Pong { nonce } => {
+                            let last_ping = *connection.last_ping.lock().unwrap();
+                            if let Some((ping_nonce, sent_at)) = last_ping {
+                                if ping_nonce == nonce {
+                                    *shared.latency.lock().unwrap() = Some(sent_at.elapsed());
+                                }
+                            }
+                        }
+                    }
+                });
+
+                while !connection.is_closed() {
+                    let err = match buffer.read(&mut recv_stream) {
+                        Ok(()) => continue,
+                        Err(err) => err,
+                    };
+
+                    let is_timeout = matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    );
+                    if is_timeout {
+                        let silence = connection.last_received.lock().unwrap().elapsed();
+                        if silence < config.heartbeat.timeout {
+                            continue;
+                        }
+                        error!("No response from server for {}s", silence.as_secs());
+                    } else if !shared.is_shutdown() {
+                        error!("Read error: {}", err);
+                    }
+
+                    connection.close();
+                    shared.queue_changed.notify_all();
+                    break;
+                }
+            });
+
+            let res = self.handshake(&mut stream, &welcome_receiver);
+            if res.is_ok() {
+                let mut queue = self.shared.queue.lock().unwrap();
+                queue.rewind();
+                if queue.is_empty() {
+                    info!("Connected to {}", self.config.remote);
+                } else {
+                    info!(
+                        "Connected to {}, resending {} queued event(s)",
+                        self.config.remote,
+                        queue.len()
+                    );
+                }
+                drop(queue);
+
+                if let Err(err) = self.send_loop(&mut stream, connection) {
+                    error!("Write error: {}", err);
+                }
+            }
+
+            // Unblock the read thread if it's still waiting for data
+            connection.close();
+            let _ = stream.shutdown(Shutdown::Both);
+            res
+        });
+
+        self.shared.stream.lock().unwrap().take();
+        *self.shared.latency.lock().unwrap() = None;
+        res
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, welcome: &Receiver<()>) -> std::io::Result<()> {
+        let hello = ClientMessage::Hello {
+            name: self.config.name.clone(),
+            version: PROTOCOL_VERSION,
+            session: self.session,
+        };
+        stream.write_all(&serialize(&hello))?;
+
+        match welcome.recv_timeout(self.config.handshake_timeout) {
+            Ok(()) => Ok(()),
+            Err(RecvTimeoutError::Timeout) => Err(std::io::ErrorKind::TimedOut.into()),
+            Err(RecvTimeoutError::Disconnected) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn send_loop(&self, stream: &mut TcpStream, connection: &Connection) -> std::io::Result<()> {
+        let mut next_ping = Instant::now();
+
+        while !connection.is_closed() && !self.shared.is_shutdown() {
+            let pending_pong = connection.pending_pong.lock().unwrap().take();
+            if let Some(nonce) = pending_pong {
+                stream.write_all(&serialize(&ClientMessage::Pong { nonce }))?;
+            }
+
+            if Instant::now() >= next_ping {
+                let nonce = rand::random();
+                *connection.last_ping.lock().unwrap() = Some((nonce, Instant::now()));
+                stream.write_all(&serialize(&ClientMessage::Ping { nonce }))?;
+                next_ping = Instant::now() + self.config.heartbeat.interval;
+            }
+
+            let mut queue = self.shared.queue.lock().unwrap();
+            let Some(queued) = queue.next_unsent() else {
+                // Wait for an event to be queued, a ping from the server, or the socket to close
+                let wait = next_ping
+                    .saturating_duration_since(Instant::now())
+                    .min(POLL_INTERVAL);
+                let _ = self.shared.queue_changed.wait_timeout(queue, wait).unwrap();
+                continue;
+            };
+            drop(queue);
+
+            info!("OUT {}", queued.event);
+            let packet = ClientMessage::Packet(ClientPacket {
+                name: self.config.name.clone(),
+                sequence: queued.sequence,
+                timestamp: queued.timestamp,
+                event: queued.event,
+            });
+            stream.write_all(&serialize(&packet))?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_reconnect(&self, failures: u32) -> bool {
+        match self.config.reconnect {
+            ReconnectPolicy::Never => false,
+            ReconnectPolicy::Backoff(backoff) => {
+                self.sleep(backoff.delay(failures));
+                !self.shared.is_shutdown()
+            }
+        }
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut remaining = duration;
+        while !remaining.is_zero() && !self.shared.is_shutdown() {
+            let step = remaining.min(POLL_INTERVAL);
+            std::thread::sleep(step);
+            remaining -= step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(jitter: f64) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let backoff = backoff(0.);
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(6), Duration::from_secs(32));
+        assert_eq!(backoff.delay(7), Duration::from_secs(60));
+        assert_eq!(backoff.delay(40), Duration::from_secs(60));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn backoff_jitter_only_shortens_delays() {
+        let backoff = backoff(0.25);
+        for _ in 0..100 {
+            let delay = backoff.delay(3);
+            assert!(delay <= Duration::from_secs(4));
+            assert!(delay >= Duration::from_secs(3));
+        }
+    }
+
+    #[test]
+    fn backoff_jitter_is_clamped() {
+        let delay = backoff(5.).delay(3);
+        assert!(delay <= Duration::from_secs(4));
+    }
+
+    #[test]
+    fn backoff_validation() {
+        assert!(backoff(0.).validate().is_ok());
+        assert!(backoff(1.).validate().is_ok());
+        assert!(backoff(-0.1).validate().is_err());
+        assert!(backoff(1.1).validate().is_err());
+        assert!(backoff(f64::NAN).validate().is_err());
+
+        let mut zero = backoff(0.);
+        zero.initial_delay = Duration::ZERO;
+        assert!(zero.validate().is_err());
+
+        let mut shrinking = backoff(0.);
+        shrinking.max_delay = Duration::from_millis(500);
+        assert!(shrinking.validate().is_err());
+    }
+}
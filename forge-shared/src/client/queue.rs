@@ -0,0 +1,352 @@
+use crate::ClientEvent;
+use log::warn;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Number of unacknowledged events kept before the overflow policy kicks in. The queue only
+    /// grows past it for joins and leaves, which are never dropped.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl QueueConfig {
+    /// Checks that the queue can hold anything, since otherwise every event is dropped.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.capacity == 0 {
+            return Err("the queue capacity can't be zero");
+        }
+        Ok(())
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 1000,
+            overflow: OverflowPolicy::DropOldestChat,
+        }
+    }
+}
+
+/// What to drop when an event is queued while the queue is full.
+///
+/// Joins and leaves are needed for moderation, so no policy drops them. The chat policies drop chat
+/// messages first, then the oldest game start, while `DropOldest` drops the oldest chat message or
+/// game start. If there's nothing left that can be dropped, an incoming chat message or game start
+/// is dropped, and an incoming join or leave is queued anyway.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    DropOldestChat,
+    DropNewestChat,
+    DropOldest,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedEvent {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub event: ClientEvent,
+}
+
+/// Events waiting to be acknowledged by the server.
+///
+/// Events stay queued after they're sent, until an acknowledgement comes back. After reconnecting
+/// the queue is rewound so everything that wasn't acknowledged is sent again.
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    config: QueueConfig,
+    next_sequence: u64,
+    events: VecDeque<QueuedEvent>,
+
+    // Number of events at the front of the queue already sent on the current connection
+    sent_count: usize,
+}
+
+impl OutgoingQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        OutgoingQueue {
+            config,
+            next_sequence: 1,
+            events: VecDeque::new(),
+            sent_count: 0,
+        }
+    }
+
+    /// Queues an event, returning an event that was dropped to make room for it.
+    pub fn push(&mut self, event: ClientEvent, timestamp: u64) -> Option<QueuedEvent> {
+        let queued = QueuedEvent {
+            sequence: self.next_sequence,
+            timestamp,
+            event,
+        };
+        self.next_sequence += 1;
+
+        if self.events.len() < self.config.capacity {
+            self.events.push_back(queued);
+            return None;
+        }
+
+        let dropped = match self.config.overflow {
+            OverflowPolicy::DropOldestChat => self.events.iter().position(is_chat),
+            OverflowPolicy::DropNewestChat => {
+                if is_chat(&queued) {
+                    return Some(queued);
+                }
+                self.events.iter().rposition(is_chat)
+            }
+            OverflowPolicy::DropOldest => None,
+        };
+        let dropped =
+            dropped.or_else(|| self.events.iter().position(|event| !is_moderation(event)));
+
+        let dropped = match dropped {
+            Some(index) => self.remove(index),
+            None if !is_moderation(&queued) => return Some(queued),
+            None => {
+                warn!(
+                    "Queue is full of joins and leaves ({} events), growing it",
+                    self.events.len()
+                );
+                None
+            }
+        };
+
+        self.events.push_back(queued);
+        dropped
+    }
+
+    /// Returns the next event that hasn't been sent on the current connection.
+    pub fn next_unsent(&mut self) -> Option<QueuedEvent> {
+        let event = self.events.get(self.sent_count)?.clone();
+        self.sent_count += 1;
+        Some(event)
+    }
+
+    /// Removes every event up to and including `sequence`.
+    pub fn ack(&mut self, sequence: u64) {
+        while let Some(event) = self.events.front() {
+            if event.sequence > sequence {
+                break;
+            }
+
+            self.events.pop_front();
+            self.sent_count = self.sent_count.saturating_sub(1);
+        }
+    }
+
+    /// Marks every queued event as unsent, so they're sent again on the next connection.
+    pub fn rewind(&mut self) {
+        self.sent_count = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn remove(&mut self, index: usize) -> Option<QueuedEvent> {
+        if index < self.sent_count {
+            self.sent_count -= 1;
+        }
+        self.events.remove(index)
+    }
+}
+
+fn is_chat(queued: &QueuedEvent) -> bool {
+    matches!(queued.event, ClientEvent::ClientChat { .. })
+}
+
+fn is_moderation(queued: &QueuedEvent) -> bool {
+    matches!(
+        queued.event,
+        ClientEvent::ClientConnecting { .. } | ClientEvent::ClientDisconnected { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(message: &str) -> ClientEvent {
+        ClientEvent::ClientChat {
+            name: "player".to_string(),
+            uid: "1".to_string(),
+            message: message.to_string(),
+            is_team: false,
+        }
+    }
+
+    fn join(name: &str) -> ClientEvent {
+        ClientEvent::ClientConnecting {
+            name: name.to_string(),
+            uid: "1".to_string(),
+        }
+    }
+
+    fn game_start() -> ClientEvent {
+        ClientEvent::GameStart {
+            map: "mp_glitch".to_string(),
+            mode: "aitdm".to_string(),
+        }
+    }
+
+    fn queue(capacity: usize, overflow: OverflowPolicy) -> OutgoingQueue {
+        OutgoingQueue::new(QueueConfig { capacity, overflow })
+    }
+
+    fn sequences(queue: &OutgoingQueue) -> Vec<u64> {
+        queue.events.iter().map(|event| event.sequence).collect()
+    }
+
+    #[test]
+    fn drop_oldest_chat_keeps_other_events() {
+        let mut queue = queue(3, OverflowPolicy::DropOldestChat);
+        queue.push(join("a"), 0);
+        queue.push(chat("one"), 0);
+        queue.push(chat("two"), 0);
+
+        let dropped = queue.push(join("b"), 0).unwrap();
+        assert_eq!(dropped.sequence, 2);
+        assert_eq!(sequences(&queue), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn drop_newest_chat_drops_incoming_chat() {
+        let mut queue = queue(2, OverflowPolicy::DropNewestChat);
+        queue.push(chat("one"), 0);
+        queue.push(chat("two"), 0);
+
+        let dropped = queue.push(chat("three"), 0).unwrap();
+        assert_eq!(dropped.sequence, 3);
+        assert_eq!(sequences(&queue), vec![1, 2]);
+
+        let dropped = queue.push(join("a"), 0).unwrap();
+        assert_eq!(dropped.sequence, 2);
+        assert_eq!(sequences(&queue), vec![1, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_joins_and_leaves() {
+        let mut queue = queue(3, OverflowPolicy::DropOldest);
+        queue.push(join("a"), 0);
+        queue.push(game_start(), 0);
+        queue.push(chat("one"), 0);
+
+        let dropped = queue.push(chat("two"), 0).unwrap();
+        assert_eq!(dropped.sequence, 2);
+        let dropped = queue.push(join("b"), 0).unwrap();
+        assert_eq!(dropped.sequence, 3);
+        assert_eq!(sequences(&queue), vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn chat_policies_drop_game_starts_once_there_is_no_chat() {
+        let mut queue = queue(2, OverflowPolicy::DropOldestChat);
+        queue.push(game_start(), 0);
+        queue.push(join("a"), 0);
+
+        let dropped = queue.push(join("b"), 0).unwrap();
+        assert_eq!(dropped.sequence, 1);
+        assert_eq!(sequences(&queue), vec![2, 3]);
+    }
+
+    #[test]
+    fn never_grows_past_capacity_without_joins_and_leaves() {
+        for overflow in [
+            OverflowPolicy::DropOldestChat,
+            OverflowPolicy::DropNewestChat,
+            OverflowPolicy::DropOldest,
+        ] {
+            let mut queue = queue(3, overflow);
+            for _ in 0..10 {
+                queue.push(game_start(), 0);
+                queue.push(chat("hello"), 0);
+            }
+            assert_eq!(queue.len(), 3, "{:?}", overflow);
+        }
+    }
+
+    #[test]
+    fn joins_and_leaves_are_never_dropped() {
+        for overflow in [
+            OverflowPolicy::DropOldestChat,
+            OverflowPolicy::DropNewestChat,
+            OverflowPolicy::DropOldest,
+        ] {
+            let mut queue = queue(2, overflow);
+            queue.push(join("a"), 0);
+            queue.push(join("b"), 0);
+
+            // Anything else is dropped as it comes in
+            assert_eq!(queue.push(chat("one"), 0).unwrap().sequence, 3);
+            assert_eq!(queue.push(game_start(), 0).unwrap().sequence, 4);
+
+            // The queue grows instead of dropping a join
+            assert!(queue.push(join("c"), 0).is_none());
+            assert_eq!(sequences(&queue), vec![1, 2, 5], "{:?}", overflow);
+        }
+    }
+
+    #[test]
+    fn zero_capacity_is_invalid() {
+        let config = QueueConfig {
+            capacity: 0,
+            overflow: OverflowPolicy::DropOldestChat,
+        };
+        assert!(config.validate().is_err());
+        assert!(QueueConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn ack_removes_acknowledged_events() {
+        let mut queue = queue(10, OverflowPolicy::DropOldestChat);
+        for _ in 0..4 {
+            queue.push(chat("hello"), 0);
+        }
+        assert_eq!(queue.next_unsent().unwrap().sequence, 1);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 2);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 3);
+
+        queue.ack(2);
+        assert_eq!(sequences(&queue), vec![3, 4]);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 4);
+        assert!(queue.next_unsent().is_none());
+
+        queue.ack(10);
+        assert!(queue.is_empty());
+        assert!(queue.next_unsent().is_none());
+    }
+
+    #[test]
+    fn rewind_resends_unacknowledged_events() {
+        let mut queue = queue(10, OverflowPolicy::DropOldestChat);
+        for _ in 0..3 {
+            queue.push(chat("hello"), 0);
+        }
+        while queue.next_unsent().is_some() {}
+        queue.ack(1);
+
+        queue.rewind();
+        assert_eq!(queue.next_unsent().unwrap().sequence, 2);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 3);
+        assert!(queue.next_unsent().is_none());
+    }
+
+    #[test]
+    fn dropping_a_sent_event_keeps_the_send_position() {
+        let mut queue = queue(2, OverflowPolicy::DropOldestChat);
+        queue.push(chat("one"), 0);
+        queue.push(chat("two"), 0);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 1);
+
+        queue.push(chat("three"), 0);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 2);
+        assert_eq!(queue.next_unsent().unwrap().sequence, 3);
+    }
+}
@@ -0,0 +1,239 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+
+// Characters the console reads as words of their own, so `kick:player` runs `kick`.
+const BREAK_CHARACTERS: [char; 6] = ['{', '}', '(', ')', '\'', ':'];
+
+/// Decides which console commands can be executed.
+///
+/// A command is allowed if nothing in `deny` or `deny-regex` matches it and, if anything is in `allow` or
+/// `allow-regex`, something there does. `allow` and `deny` are compared with the first word of the command as the
+/// console reads it, without quotes and ignoring case. The regexes can match anywhere in the command, unless they're
+/// anchored with `^` and `$`.
+///
+/// The console runs each part of a line separated by `;` or a line break as its own command, so each part has to be
+/// allowed. See [`split_commands`].
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(try_from = "CommandFilterConfig")]
+pub struct CommandFilter {
+    allow: Vec<String>,
+    allow_regex: Vec<Regex>,
+    deny: Vec<String>,
+    deny_regex: Vec<Regex>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct CommandFilterConfig {
+    allow: Vec<String>,
+    allow_regex: Vec<String>,
+    deny: Vec<String>,
+    deny_regex: Vec<String>,
+}
+
+/// A command that a [`CommandFilter`] doesn't allow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandDenied {
+    pub command: String,
+}
+
+impl Display for CommandDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` isn't allowed", self.command)
+    }
+}
+
+impl std::error::Error for CommandDenied {}
+
+impl TryFrom<CommandFilterConfig> for CommandFilter {
+    type Error = regex::Error;
+
+    fn try_from(config: CommandFilterConfig) -> Result<Self, Self::Error> {
+        let compile = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let lowercase = |words: Vec<String>| {
+            words
+                .into_iter()
+                .map(|word| word.to_ascii_lowercase())
+                .collect()
+        };
+
+        Ok(CommandFilter {
+            allow: lowercase(config.allow),
+            allow_regex: compile(config.allow_regex)?,
+            deny: lowercase(config.deny),
+            deny_regex: compile(config.deny_regex)?,
+        })
+    }
+}
+
+impl CommandFilter {
+    /// Checks every command in a line.
+    pub fn check(&self, line: &str) -> Result<(), CommandDenied> {
+        for command in split_commands(line) {
+            if !self.allows(command) {
+                return Err(CommandDenied {
+                    command: command.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn allows(&self, command: &str) -> bool {
+        let first_word = first_word(command).to_ascii_lowercase();
+
+        let denied = self.deny.contains(&first_word)
+            || self.deny_regex.iter().any(|regex| regex.is_match(command));
+        let allowed = (self.allow.is_empty() && self.allow_regex.is_empty())
+            || self.allow.contains(&first_word)
+            || self.allow_regex.iter().any(|regex| regex.is_match(command));
+
+        allowed && !denied
+    }
+}
+
+/// Splits a line into the commands the console runs, like the Source engine does: at each `;` that isn't inside quotes,
+/// and at every line break. Empty commands are left out.
+pub fn split_commands(line: &str) -> Vec<&str> {
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        let ends_command = match c {
+            '"' => {
+                quoted = !quoted;
+                false
+            }
+            ';' => !quoted,
+            // Quotes can't carry on to the next line
+            '\n' | '\r' => {
+                quoted = false;
+                true
+            }
+            _ => false,
+        };
+        if ends_command {
+            commands.push(&line[start..index]);
+            start = index + 1;
+        }
+    }
+    commands.push(&line[start..]);
+
+    commands
+        .into_iter()
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+        .collect()
+}
+
+/// The first word of a command, which is everything in the first quotes if it starts with them.
+fn first_word(command: &str) -> &str {
+    let command = command.trim_start();
+    if let Some(quoted) = command.strip_prefix('"') {
+        return quoted.split('"').next().unwrap_or_default();
+    }
+    let end = command
+        .find(|c: char| c.is_whitespace() || c == '"' || BREAK_CHARACTERS.contains(&c))
+        .unwrap_or(command.len());
+    if end == 0 {
+        // A break character on its own
+        return &command[..command.chars().next().map_or(0, char::len_utf8)];
+    }
+    &command[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(
+        allow: &[&str],
+        allow_regex: &[&str],
+        deny: &[&str],
+        deny_regex: &[&str],
+    ) -> CommandFilter {
+        let strings = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        CommandFilter::try_from(CommandFilterConfig {
+            allow: strings(allow),
+            allow_regex: strings(allow_regex),
+            deny: strings(deny),
+            deny_regex: strings(deny_regex),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let filter = CommandFilter::default();
+        assert!(filter.check("kick player").is_ok());
+        assert!(filter.check("").is_ok());
+    }
+
+    #[test]
+    fn allows_only_listed_commands() {
+        let filter = filter(&["kick", "Status"], &[], &[], &[]);
+        assert!(filter.check("kick player").is_ok());
+        assert!(filter.check("STATUS").is_ok());
+        assert!(filter.check("  kick\tplayer").is_ok());
+        assert_eq!(
+            filter.check("kick player; quit"),
+            Err(CommandDenied {
+                command: "quit".to_string()
+            })
+        );
+        assert!(filter.check("kickall").is_err());
+    }
+
+    #[test]
+    fn denies_listed_commands() {
+        let filter = filter(&[], &[], &["quit"], &[]);
+        assert!(filter.check("kick player").is_ok());
+        assert!(filter.check("Quit").is_err());
+        assert!(filter.check("status\nquit").is_err());
+        assert!(filter.check("status\r\nquit").is_err());
+        assert!(filter.check("status;;quit;").is_err());
+    }
+
+    #[test]
+    fn matches_regexes_anywhere() {
+        let filter = filter(&[], &["^say "], &[], &["password"]);
+        assert!(filter.check("say hello").is_ok());
+        assert!(filter.check("kick player").is_err());
+        assert!(filter.check("say my password").is_err());
+        // Regexes are case-sensitive unless they ask not to be
+        assert!(filter.check("SAY hello").is_err());
+    }
+
+    #[test]
+    fn reads_commands_like_the_console() {
+        let filter = filter(&["say"], &[], &["quit"], &[]);
+        // Quotes around the command name are removed
+        assert!(filter.check("\"quit\"").is_err());
+        assert!(filter.check("\"QUIT\" now").is_err());
+        // A `;` inside quotes doesn't end the command
+        assert!(filter.check("say \"hello; quit\"").is_ok());
+        assert!(filter.check("say \"hello\"; quit").is_err());
+        // But a line break always does
+        assert!(filter.check("say \"hello\nquit").is_err());
+        // Break characters end the first word
+        assert!(filter.check("quit:now").is_err());
+        assert!(filter.check("say(hello)").is_ok());
+    }
+
+    #[test]
+    fn splits_commands() {
+        assert_eq!(
+            split_commands(" status ; say \"a;b\"\nkick x ;"),
+            ["status", "say \"a;b\"", "kick x"]
+        );
+        assert!(split_commands(" ; \n").is_empty());
+        assert_eq!(first_word("\"kick player\" x"), "kick player");
+        assert_eq!(first_word("'quoted'"), "'");
+    }
+}
@@ -2,6 +2,12 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
+pub mod client;
+pub mod filter;
+
+/// Version of the wire protocol, exchanged when a client connects.
+pub const PROTOCOL_VERSION: u32 = 6;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ClientEvent {
     GameStart {
@@ -27,20 +33,70 @@ pub enum ClientEvent {
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ClientPacket {
     pub name: String,
+    /// Increases by one for each event produced by a client session. Gaps mean the client dropped
+    /// events, and repeats mean an event was resent after reconnecting.
+    pub sequence: u64,
+    /// When the event happened on the game server, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
     pub event: ClientEvent,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// Sent once after connecting. `session` is random for each run of the client, so the server
+    /// knows when sequence numbers start over.
+    Hello {
+        name: String,
+        version: u32,
+        session: u64,
+    },
+    Packet(ClientPacket),
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ServerEvent {
     ExecCommand { command: String },
+    /// Broadcasts a chat message to every player.
+    Chat { message: String },
+}
+
+/// Which clients a server packet is for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Target {
+    All,
+    Names(Vec<String>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ServerPacket {
-    pub name: Option<String>,
+    pub target: Target,
     pub event: ServerEvent,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    Welcome,
+    /// Acknowledges every packet up to and including `sequence`.
+    Ack { sequence: u64 },
+    Packet(ServerPacket),
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+impl ClientEvent {
+    /// The name of the event, like `ClientChat`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientEvent::GameStart { .. } => "GameStart",
+            ClientEvent::ClientConnecting { .. } => "ClientConnecting",
+            ClientEvent::ClientDisconnected { .. } => "ClientDisconnected",
+            ClientEvent::ClientChat { .. } => "ClientChat",
+        }
+    }
+}
+
 impl std::fmt::Display for ClientEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -58,10 +114,52 @@ impl std::fmt::Display for ClientEvent {
     }
 }
 
+impl Target {
+    /// Targets one client.
+    pub fn name(name: impl Into<String>) -> Self {
+        Target::Names(vec![name.into()])
+    }
+
+    /// Targets one client, or every client if `name` is `None`.
+    pub fn from_name(name: Option<String>) -> Self {
+        match name {
+            Some(name) => Target::name(name),
+            None => Target::All,
+        }
+    }
+
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            Target::All => true,
+            Target::Names(names) => names.iter().any(|target| target == name),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::All => write!(f, "<everyone>"),
+            Target::Names(names) => write!(f, "{}", names.join(", ")),
+        }
+    }
+}
+
+impl ServerEvent {
+    /// The name of the event, like `ExecCommand`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerEvent::ExecCommand { .. } => "ExecCommand",
+            ServerEvent::Chat { .. } => "Chat",
+        }
+    }
+}
+
 impl std::fmt::Display for ServerEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ServerEvent::ExecCommand { command } => write!(f, "ExecCommand command={command}"),
+            ServerEvent::Chat { message } => write!(f, "Chat message={message}"),
         }
     }
 }
@@ -85,13 +183,19 @@ impl<T: DeserializeOwned, F: FnMut(T)> ReceiveBuffer<T, F> {
 
     pub fn read<R: std::io::Read>(&mut self, mut r: R) -> std::io::Result<()> {
         let mut read = self.start_read();
-        let write_len = r.read(read.data())?;
+        let write_len = match r.read(read.data()) {
+            Ok(write_len) => write_len,
+            Err(err) => {
+                // Keep the buffer intact so reading can continue after a timeout
+                let _ = read.finish(0);
+                return Err(err);
+            }
+        };
         if write_len == 0 {
             return Err(std::io::ErrorKind::UnexpectedEof.into())
         }
 
-        read.finish(write_len);
-        Ok(())
+        read.finish(write_len)
     }
 
     pub fn start_read(&mut self) -> ReceiveBufferRead<T, F> {
@@ -115,7 +219,9 @@ impl<'b, T: DeserializeOwned, F: FnMut(T)> ReceiveBufferRead<'b, T, F> {
         &mut self.buffer.data[self.start_index..]
     }
 
-    pub fn finish(self, write_len: usize) {
+    /// Parses every complete message that has been read. Fails if a message can't be decoded, after
+    /// which the buffer shouldn't be used.
+    pub fn finish(self, write_len: usize) -> std::io::Result<()> {
         let buffer = self.buffer;
         buffer.data.truncate(self.start_index + write_len);
 
@@ -134,13 +240,15 @@ impl<'b, T: DeserializeOwned, F: FnMut(T)> ReceiveBufferRead<'b, T, F> {
             }
 
             let read_slice = &remaining_bytes[..len];
-            read_index += std::mem::size_of::<u32>() + remaining_bytes.len();
+            read_index += std::mem::size_of::<u32>() + len;
 
-            let val = bincode::deserialize(read_slice).expect("bincode deserialize failed");
+            let val = bincode::deserialize(read_slice)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
             (buffer.on_parsed)(val);
         }
 
         buffer.data.drain(..read_index);
+        Ok(())
     }
 }
 
@@ -154,3 +262,68 @@ pub fn serialize<T: Serialize>(val: &T) -> Vec<u8> {
     data[..u32_size].copy_from_slice(&(val_size as u32).to_ne_bytes());
     data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(message: &str) -> ServerMessage {
+        ServerMessage::Packet(ServerPacket {
+            target: Target::All,
+            event: ServerEvent::Chat {
+                message: message.to_string(),
+            },
+        })
+    }
+
+    fn receive(chunks: &[&[u8]]) -> std::io::Result<Vec<ServerMessage>> {
+        let mut received = Vec::new();
+        let mut buffer = ReceiveBuffer::new(|message| received.push(message));
+        for chunk in chunks {
+            let mut read = buffer.start_read();
+            read.data()[..chunk.len()].copy_from_slice(chunk);
+            read.finish(chunk.len())?;
+        }
+        drop(buffer);
+        Ok(received)
+    }
+
+    #[test]
+    fn parses_several_messages_from_one_read() {
+        let mut data = serialize(&chat("one"));
+        data.extend(serialize(&ServerMessage::Welcome));
+        data.extend(serialize(&chat("two")));
+
+        let received = receive(&[&data]).unwrap();
+        assert_eq!(
+            received,
+            vec![chat("one"), ServerMessage::Welcome, chat("two")]
+        );
+    }
+
+    #[test]
+    fn parses_messages_split_across_reads() {
+        let mut data = serialize(&chat("one"));
+        data.extend(serialize(&chat("two")));
+        let (first, second) = data.split_at(6);
+
+        let received = receive(&[first, second]).unwrap();
+        assert_eq!(received, vec![chat("one"), chat("two")]);
+    }
+
+    #[test]
+    fn keeps_incomplete_messages() {
+        let data = serialize(&chat("one"));
+        let received = receive(&[&data[..data.len() - 1]]).unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn fails_on_undecodable_messages() {
+        let mut data = 4u32.to_ne_bytes().to_vec();
+        data.extend([0xFF; 4]);
+
+        let err = receive(&[&data]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{anyhow, bail, Result};
+use forge_shared::ClientEvent;
+
+pub enum Command {
+    Event(ClientEvent),
+    Disconnect,
+    Quit,
+}
+
+pub const HELP: &str = "\
+Commands (prefix with a server name when simulating more than one):
+  start <map> <mode>
+  join <player> [uid]
+  leave <player> [uid]
+  chat <player> <message>
+  team <player> <message>
+  event <ClientEvent as JSON>
+  disconnect
+  quit";
+
+/// Parses a line of input, returning the targeted server name and the command.
+pub fn parse_line<'a>(line: &str, names: &'a [String]) -> Result<(&'a str, Command)> {
+    let line = line.trim();
+
+    let (name, line) = match line.split_once(' ') {
+        Some((first, rest)) if names.iter().any(|name| name == first) => {
+            let name = names.iter().find(|name| *name == first).unwrap();
+            (name.as_str(), rest.trim_start())
+        }
+        _ if names.len() == 1 => (names[0].as_str(), line),
+        _ => bail!("Start the command with one of: {}", names.join(", ")),
+    };
+
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    let command = match command {
+        "start" => {
+            let (map, mode) = split_arg(args).ok_or_else(|| anyhow!("Usage: start <map> <mode>"))?;
+            Command::Event(ClientEvent::GameStart {
+                map: map.to_string(),
+                mode: mode.to_string(),
+            })
+        }
+        "join" => {
+            let (name, uid) = player_args(args)?;
+            Command::Event(ClientEvent::ClientConnecting { name, uid })
+        }
+        "leave" => {
+            let (name, uid) = player_args(args)?;
+            Command::Event(ClientEvent::ClientDisconnected { name, uid })
+        }
+        "chat" | "team" => {
+            let (name, message) =
+                split_arg(args).ok_or_else(|| anyhow!("Usage: {command} <player> <message>"))?;
+            Command::Event(ClientEvent::ClientChat {
+                name: name.to_string(),
+                uid: "0".to_string(),
+                message: message.to_string(),
+                is_team: command == "team",
+            })
+        }
+        "event" => Command::Event(serde_json::from_str(args)?),
+        "disconnect" => Command::Disconnect,
+        "quit" => Command::Quit,
+        _ => bail!("Unknown command `{command}`"),
+    };
+
+    Ok((name, command))
+}
+
+fn split_arg(args: &str) -> Option<(&str, &str)> {
+    let (first, rest) = args.split_once(' ')?;
+    Some((first, rest.trim_start()))
+}
+
+fn player_args(args: &str) -> Result<(String, String)> {
+    let (name, uid) = split_arg(args).unwrap_or((args, "0"));
+    if name.is_empty() {
+        bail!("Expected a player name");
+    }
+    Ok((name.to_string(), uid.to_string()))
+}
@@ -0,0 +1,181 @@
+use crate::interactive::Command;
+use forge_shared::client::{Client, ClientConfig, ClientHandle};
+use log::{error, info, LevelFilter};
+use std::io::BufRead;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+mod interactive;
+mod scenario;
+
+// How long to wait for queued events to be acknowledged before exiting.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Mode {
+    Scenario(PathBuf),
+    Interactive(Vec<String>),
+}
+
+struct Args {
+    remote: Option<SocketAddr>,
+    stay: bool,
+    mode: Mode,
+}
+
+fn main() {
+    simple_logger::SimpleLogger::new()
+        .with_utc_timestamps()
+        .with_level(LevelFilter::Off)
+        .with_module_level("forge", LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let mut args = std::env::args();
+    let exe_name = args.next().unwrap();
+
+    let Some(args) = parse_args(args) else {
+        eprintln!("Usage: {} [--remote <address>] [--stay] <scenario file>", exe_name);
+        eprintln!("       {} --remote <address> --interactive <server name>...", exe_name);
+        eprintln!();
+        std::process::exit(1);
+    };
+
+    match args.mode {
+        Mode::Scenario(path) => run_scenario(&path, args.remote, args.stay),
+        Mode::Interactive(names) => {
+            let Some(remote) = args.remote else {
+                eprintln!("--remote is required in interactive mode");
+                std::process::exit(1);
+            };
+            run_interactive(names, remote);
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut remote = None;
+    let mut stay = false;
+    let mut interactive = false;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--remote" => remote = Some(args.next()?.parse().ok()?),
+            "--stay" => stay = true,
+            "--interactive" => interactive = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let mode = if interactive {
+        if positional.is_empty() {
+            return None;
+        }
+        Mode::Interactive(positional)
+    } else {
+        let [path] = <[String; 1]>::try_from(positional).ok()?;
+        Mode::Scenario(path.into())
+    };
+
+    Some(Args { remote, stay, mode })
+}
+
+fn spawn_client(name: &str, remote: SocketAddr) -> (ClientHandle, JoinHandle<()>) {
+    let (client, handle) = Client::new(ClientConfig::new(name, remote));
+
+    let name = name.to_string();
+    let thread = std::thread::spawn(move || {
+        client.run(|packet| println!("[{}] {}", name, packet.event));
+    });
+
+    (handle, thread)
+}
+
+/// Waits for every queued event to be acknowledged, then shuts the client down.
+fn drain_and_shutdown(handle: &ClientHandle) {
+    let start = Instant::now();
+    while handle.queued() > 0 && start.elapsed() < DRAIN_TIMEOUT {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if handle.queued() > 0 {
+        error!("Gave up with {} unacknowledged event(s)", handle.queued());
+    }
+    handle.shutdown();
+}
+
+fn run_scenario(path: &std::path::Path, remote: Option<SocketAddr>, stay: bool) {
+    let scenario = match scenario::load(path) {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            error!("Failed to read scenario file: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let Some(remote) = remote.or(scenario.remote) else {
+        error!("No remote address given in the scenario or with --remote");
+        std::process::exit(1);
+    };
+
+    std::thread::scope(|s| {
+        for server in &scenario.servers {
+            s.spawn(move || {
+                let (handle, client_thread) = spawn_client(&server.name, remote);
+
+                if let Some(flaky) = &server.flaky {
+                    let handle = handle.clone();
+                    s.spawn(move || scenario::run_flaky(&server.name, flaky, &handle));
+                }
+
+                scenario::run_steps(server, &handle);
+                info!("[{}] Finished scenario", server.name);
+
+                if !stay {
+                    drain_and_shutdown(&handle);
+                }
+                client_thread.join().unwrap();
+            });
+        }
+    });
+}
+
+fn run_interactive(names: Vec<String>, remote: SocketAddr) {
+    let clients: Vec<_> = names
+        .iter()
+        .map(|name| (name.clone(), spawn_client(name, remote)))
+        .collect();
+
+    eprintln!("{}", interactive::HELP);
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("Failed to read stdin");
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (name, command) = match interactive::parse_line(&line, &names) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let (_, (handle, _)) = clients
+            .iter()
+            .find(|(client_name, _)| client_name == name)
+            .unwrap();
+
+        match command {
+            Command::Event(event) => handle.send(event),
+            Command::Disconnect => handle.disconnect(),
+            Command::Quit => break,
+        }
+    }
+
+    for (_, (handle, thread)) in clients {
+        drain_and_shutdown(&handle);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use forge_shared::client::ClientHandle;
+use forge_shared::ClientEvent;
+use log::info;
+use rand::Rng;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Scenario {
+    pub remote: Option<SocketAddr>,
+    pub servers: Vec<ServerScenario>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerScenario {
+    pub name: String,
+    /// Start again from the first step after finishing the last one.
+    #[serde(default)]
+    pub repeat: bool,
+    pub flaky: Option<FlakyConfig>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+/// Drops the connection after a random amount of time connected.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlakyConfig {
+    pub min_uptime_ms: u64,
+    pub max_uptime_ms: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Step {
+    /// Time to wait before running this step.
+    #[serde(default)]
+    pub wait_ms: u64,
+    pub event: Option<ClientEvent>,
+    #[serde(default)]
+    pub disconnect: bool,
+}
+
+/// Loads a scenario from a JSON file if it has a `.json` extension, or a TOML file otherwise.
+pub fn load(path: &Path) -> Result<Scenario> {
+    let contents = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => Ok(serde_json::from_str(&contents)?),
+        _ => Ok(toml::from_str(&contents)?),
+    }
+}
+
+pub fn run_steps(server: &ServerScenario, handle: &ClientHandle) {
+    loop {
+        for step in &server.steps {
+            std::thread::sleep(Duration::from_millis(step.wait_ms));
+
+            if let Some(event) = &step.event {
+                handle.send(event.clone());
+            }
+            if step.disconnect {
+                info!("[{}] Dropping connection", server.name);
+                handle.disconnect();
+            }
+        }
+
+        if !server.repeat || server.steps.is_empty() {
+            break;
+        }
+    }
+}
+
+pub fn run_flaky(name: &str, flaky: &FlakyConfig, handle: &ClientHandle) {
+    let mut rng = rand::thread_rng();
+    while !handle.is_shutdown() {
+        let uptime = rng.gen_range(flaky.min_uptime_ms..=flaky.max_uptime_ms.max(flaky.min_uptime_ms));
+        std::thread::sleep(Duration::from_millis(uptime));
+
+        info!("[{}] Dropping connection", name);
+        handle.disconnect();
+    }
+}